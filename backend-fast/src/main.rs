@@ -1,41 +1,139 @@
 use axum::{
     body::Body,
-    extract::{ConnectInfo, State},
-    http::{Method, Request},
-    middleware::{from_fn, Next},
-    response::IntoResponse,
+    extract::{ConnectInfo, Extension, Query, State},
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE, STRICT_TRANSPORT_SECURITY},
+        HeaderMap, HeaderName, HeaderValue, Method, Request, StatusCode, Uri,
+    },
+    middleware::{from_fn, from_fn_with_state, Next},
+    response::{IntoResponse, Redirect, Response},
     routing::{get, post},
     Json, Router,
 };
 use blake3::hash;
+use data_encoding::{BASE32_NOPAD, BASE64URL_NOPAD, HEXLOWER};
+use hmac::{Hmac, Mac};
+use qrcode::{render::svg, QrCode};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
 use std::{
     cmp::Ordering,
-    collections::{BTreeSet, HashMap},
+    collections::{BTreeMap, BTreeSet, HashMap},
+    env::args,
     error::Error,
-    fs::read_to_string,
-    io::Write,
-    net::SocketAddr,
+    fmt,
+    fs::{create_dir_all, read_to_string},
+    io::{BufRead, BufReader, Write},
+    net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs},
     path::Path,
     sync::{Arc, Mutex, OnceLock},
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use tower_http::cors::{Any, CorsLayer};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::skeleton;
 
 // TODO: show solved chals in /challenges, /profile (maybe /scoreboard)
-// TODO: use rust-argon2 instead of blake3 for password hashing (AUTH_SECRET as salt?)
-// TODO: dynamic scoring system
-// TODO: change_pass, ?admin panel?, your position on the scoreboard page (above the table)
+// TODO: ?admin panel?, your position on the scoreboard page (above the table)
 // TODO: hostctf frontend render "your position"
 //       (backend should return an array with two things: 1.logged_user_stats 2.all_users_or_first_X_users)
 
 const CTF_STARTED: bool = true;
-const AUTH_SECRET: &str = "CHANGE_ME!"; // NOTE: use tower auth layer instead?
+const ADMIN_USERS: &[&str] = &[]; // promoted to admin on every start, the set-role command does the rest
+const SESSION_LIFETIME: u64 = 7 * 24 * 60 * 60; // in seconds
+const PASSWORD_RESET_LIFETIME: u64 = 30 * 60; // in seconds
+const PASSWORD_RESET_URL: &str = "http://localhost:8080/reset_password?token="; // frontend page
+const MAIL_FROM: &str = "hostctf@localhost";
+const MAIL_TRANSPORT: MailTransport = MailTransport::Directory("./mail");
+// const MAIL_TRANSPORT: MailTransport = MailTransport::Smtp { host: "127.0.0.1", port: 1025 }; // MailHog
+const SMTP_TIMEOUT: u64 = 10; // in seconds, for connecting and for every read and write
+const REGISTRATION_MODE: RegistrationMode = RegistrationMode::Open;
+// const REGISTRATION_MODE: RegistrationMode = RegistrationMode::EmailDomains(&["example.edu"]);
 const ENABLE_STDOUT_EVENT_LOGS: bool = true;
+// how sensitive fields are written to the event and request logs, see Redaction
+const LOG_FLAGS: Redaction = Redaction::Hash;
+const LOG_EMAILS: Redaction = Redaction::Mask;
+const LOG_QUERY_SECRETS: Redaction = Redaction::Hidden; // values of SENSITIVE_QUERY_PARAMS in request urls
+const SENSITIVE_QUERY_PARAMS: &[&str] = &["token", "code", "state"]; // reset tokens and OIDC callbacks
+const AUDIT_LOG_FILE: &str = "./audit.jsonl"; // append-only record of admin actions, next to database.db
 
-static CHALLENGES: OnceLock<String> = OnceLock::new();
+// argon2id parameters for password hashing (defaults are the OWASP recommendation)
+const ARGON2_MEM_COST: u32 = 19456; // in KiB
+const ARGON2_TIME_COST: u32 = 2;
+const ARGON2_LANES: u32 = 1;
+// username rules, checked after NFKC normalization
+const USERNAME_ASCII_ONLY: bool = false; // true rejects letters and digits outside of ASCII
+const USERNAME_EXTRA_CHARS: &str = "_-."; // allowed besides letters and digits
+const USERNAME_MIN_LENGTH: usize = 3; // in characters
+const USERNAME_MAX_LENGTH: usize = 32;
+// token buckets, every client ip and every username gets its own
+const LOGIN_RATE_LIMIT: RateLimit = RateLimit {
+    burst: 10,
+    per_minute: 10,
+};
+const FLAG_SUBMIT_RATE_LIMIT: RateLimit = RateLimit {
+    burst: 10,
+    per_minute: 30,
+};
+const PASSWORD_RESET_RATE_LIMIT: RateLimit = RateLimit {
+    burst: 3,
+    per_minute: 1,
+};
+const LOCKOUT_THRESHOLD: u32 = 5; // consecutive wrong passwords which lock the account
+const LOCKOUT_DURATIONS: &[u64] = &[5 * 60, 30 * 60, 2 * 60 * 60, 24 * 60 * 60]; // in seconds, grows with every lockout
+
+// RFC 6238 parameters, the defaults every authenticator app understands
+const TOTP_ISSUER: &str = "HostCTF";
+const TOTP_STEP: u64 = 30; // in seconds
+const TOTP_DIGITS: u32 = 6;
+const TOTP_SKEW: u64 = 1; // steps accepted on each side of the current one
+const TOTP_RECOVERY_CODES: usize = 8;
+const LOGIN_2FA_LIFETIME: u64 = 5 * 60; // in seconds, time to enter the code after an OIDC login
+
+// OpenID Connect login through an external identity provider, an empty issuer disables it
+const OIDC_ISSUER: &str = ""; // e.g. "https://idp.example.edu/realms/campus"
+const OIDC_CLIENT_ID: &str = "hostctf";
+const OIDC_CLIENT_SECRET: &str = ""; // empty for public clients, PKCE protects the code either way
+const OIDC_REDIRECT_URL: &str = "http://localhost:3000/oidc/callback";
+const OIDC_FRONTEND_URL: &str = "http://localhost:8080/oidc"; // gets the result in the url fragment
+const OIDC_FLOW_LIFETIME: u64 = 10 * 60; // in seconds
+const API_TOKEN_PREFIX: &str = "hctf_"; // makes leaked tokens easy to grep for
+const API_TOKEN_LAST_USED_PRECISION: u64 = 60; // in seconds, spares a DB write on every request
+
+// headers added to every response, remove an entry to leave that header out
+const SECURITY_HEADERS: &[(&str, &str)] = &[
+    // the api only serves json, so nothing may be loaded or framed
+    (
+        "content-security-policy",
+        "default-src 'none'; frame-ancestors 'none'",
+    ),
+    ("x-frame-options", "DENY"),
+    ("referrer-policy", "strict-origin-when-cross-origin"),
+    ("x-content-type-options", "nosniff"),
+    (
+        "permissions-policy",
+        "camera=(), microphone=(), geolocation=(), payment=(), usb=()",
+    ),
+];
+const HSTS_MAX_AGE: Option<u64> = None; // in seconds, e.g. Some(365 * 24 * 60 * 60). only set it when served over https
+
+// CORS for frontends served from another origin, an empty list of origins turns it off
+const CORS_ALLOWED_ORIGINS: &[&str] = &["*"]; // any origin, or e.g. &["https://ctf.example.edu", "http://localhost:5173"]
+const CORS_ALLOWED_METHODS: &[&str] = &["GET", "POST"];
+const CORS_ALLOWED_HEADERS: &[&str] = &["content-type", "authorization"];
+const CORS_ALLOW_CREDENTIALS: bool = false; // the frontends send the auth_key in the body, not as a cookie
+const CORS_MAX_AGE: u64 = 60 * 60; // in seconds, how long browsers may cache a preflight response
+
+static CHALLENGES: Mutex<String> = Mutex::new(String::new());
 static CHALLENGES_JSON: OnceLock<Vec<ChallengeCategory>> = OnceLock::new();
 static SCOREBOARD_CACHE: Mutex<String> = Mutex::new(String::new());
+static SECURITY_HEADER_MAP: OnceLock<HeaderMap> = OnceLock::new();
+static LOG_HASH_KEY: OnceLock<[u8; 32]> = OnceLock::new();
+static FLAG_SECRET: OnceLock<String> = OnceLock::new();
+// (challenge id, dynamic flag) -> username, so a shared flag is found without an HMAC per user
+static DYNAMIC_FLAG_OWNERS: Mutex<BTreeMap<(u16, String), String>> = Mutex::new(BTreeMap::new());
 
 const RED: &str = "\x1b[31m";
 const GREEN: &str = "\x1b[32m";
@@ -44,14 +142,331 @@ const BLUE: &str = "\x1b[34m";
 const GRAY: &str = "\x1b[90m";
 const RESET: &str = "\x1b[00m";
 
+#[derive(Debug)]
+struct RateLimit {
+    // requests which can be made at once
+    burst: u32,
+    // rate at which the bucket refills
+    per_minute: u32,
+}
+
+#[allow(dead_code)] // only one of the transports is configured at a time
+enum MailTransport {
+    // plain SMTP without authentication or TLS, meant for a local relay or sink
+    Smtp { host: &'static str, port: u16 },
+    // every mail is written into the directory as an .eml file
+    Directory(&'static str),
+}
+
+#[allow(dead_code)] // only one of the modes is configured at a time
+enum RegistrationMode {
+    Open,
+    Closed,
+    // every new account uses up an invite code, admins create them with /admin/invites/create
+    InviteCode,
+    // only e-mail addresses from these domains (exact match, subdomains have to be listed)
+    EmailDomains(&'static [&'static str]),
+}
+
+impl RegistrationMode {
+    fn name(&self) -> &'static str {
+        match self {
+            RegistrationMode::Open => "open",
+            RegistrationMode::Closed => "closed",
+            RegistrationMode::InviteCode => "invite code",
+            RegistrationMode::EmailDomains(_) => "e-mail domain allowlist",
+        }
+    }
+}
+
 #[derive(Deserialize, Debug, Serialize)]
 struct Challenge {
     id: u16,
     name: String,
     description: String,
-    hint: Option<String>,
+    // free ones are shown right away, the others have to be unlocked for their cost
+    #[serde(
+        default,
+        skip_serializing,
+        alias = "hint",
+        deserialize_with = "deserialize_hints"
+    )]
+    hints: Vec<Hint>,
+    // not needed with dynamic scoring
+    #[serde(default)]
     points: u32,
-    flag: String,
+    // opt-in, the value decays with every solve
+    #[serde(default, skip_serializing)]
+    scoring: Option<Scoring>,
+    // extra percent of the value for the first, second, ... solver, e.g. [10, 5, 2]
+    #[serde(default, skip_serializing)]
+    first_blood_bonus: Vec<u32>,
+    #[serde(skip_serializing)]
+    flag: FlagSpec,
+    // the description is HTML the frontends may render as is
+    #[serde(default)]
+    trusted_html: bool,
+}
+
+impl Challenge {
+    // what every solver of the challenge gets right now
+    fn current_value(&self, solve_counts: &HashMap<u16, u32>) -> u32 {
+        self.value_at(solve_counts.get(&self.id).copied().unwrap_or(0))
+    }
+
+    fn value_at(&self, solves: u32) -> u32 {
+        match &self.scoring {
+            Some(scoring) => scoring.value(solves),
+            None => self.points,
+        }
+    }
+
+    // rank 1 is the first blood, the bonus is fixed with the value at the time of the solve
+    fn solve_bonus(&self, rank: u32) -> Option<SolveBonus> {
+        let percent = *self.first_blood_bonus.get(rank.checked_sub(1)? as usize)?;
+        Some(SolveBonus {
+            challenge_id: self.id,
+            rank,
+            points: self.value_at(rank) * percent / 100,
+        })
+    }
+}
+
+// in challenges.json a string, or a list of strings and {"text": "...", "cost": 50}
+#[derive(Deserialize, Debug)]
+struct Hint {
+    text: String,
+    #[serde(default)]
+    cost: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum HintsConfig {
+    One(String),
+    Many(Vec<HintConfig>),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum HintConfig {
+    Free(String),
+    Priced(Hint),
+}
+
+fn deserialize_hints<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<Hint>, D::Error> {
+    Ok(match Option::<HintsConfig>::deserialize(deserializer)? {
+        None => Vec::new(),
+        Some(HintsConfig::One(text)) => vec![Hint { text, cost: 0 }],
+        Some(HintsConfig::Many(hints)) => hints
+            .into_iter()
+            .map(|hint| match hint {
+                HintConfig::Free(text) => Hint { text, cost: 0 },
+                HintConfig::Priced(hint) => hint,
+            })
+            .collect(),
+    })
+}
+
+// CTFd-style decay from `initial` down to `minimum`, reached at `decay` solves, e.g.
+// "scoring": {"function": "logarithmic", "initial": 500, "minimum": 100, "decay": 20}
+#[derive(Deserialize, Debug)]
+struct Scoring {
+    function: DecayFunction,
+    initial: u32,
+    minimum: u32,
+    decay: u32,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+enum DecayFunction {
+    // slow at first, then faster (the parabola CTFd calls logarithmic)
+    Logarithmic,
+    Linear,
+}
+
+impl Scoring {
+    // checked when the challenges are loaded
+    fn config_error(&self) -> Option<&'static str> {
+        if self.minimum > self.initial {
+            Some("scoring minimum is above initial")
+        } else if self.decay == 0 {
+            // every solver, even the first one, would only get the minimum
+            Some("scoring decay has to be at least 1")
+        } else {
+            None
+        }
+    }
+
+    fn value(&self, solves: u32) -> u32 {
+        // the first solver doesn't lower the value yet
+        let solves = solves.saturating_sub(1);
+        if solves >= self.decay {
+            return self.minimum;
+        }
+        let (initial, minimum, decay) = (
+            f64::from(self.initial),
+            f64::from(self.minimum),
+            f64::from(self.decay),
+        );
+        let solves = f64::from(solves);
+        let value = match self.function {
+            DecayFunction::Logarithmic => {
+                (minimum - initial) / (decay * decay) * solves * solves + initial
+            }
+            DecayFunction::Linear => initial - (initial - minimum) * solves / decay,
+        };
+        (value.ceil() as u32).max(self.minimum)
+    }
+}
+
+// accepted answers of a challenge. in challenges.json either a plain string (matched exactly),
+// one typed entry or a list mixing both, e.g.
+// [{"type": "static_ci", "value": "flag{a}", "trim": true}, {"type": "regex", "value": "flag\\{b+\\}"}]
+// a dynamic entry gives every player their own flag, see dynamic_flag()
+#[derive(Debug)]
+struct FlagSpec(Vec<FlagRule>);
+
+#[derive(Debug)]
+struct FlagRule {
+    kind: FlagKind,
+    // lowercased for static_ci, the anchored pattern for regex, the prefix for dynamic
+    value: String,
+    regex: Option<Regex>,
+    // ignore whitespace around the submission
+    trim: bool,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+enum FlagKind {
+    #[default]
+    Static,
+    StaticCi,
+    // has to match the whole submission
+    Regex,
+    // value{hmac of the challenge and the username}, sharing it with others gets noticed
+    Dynamic,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FlagSpecConfig {
+    One(FlagRuleConfig),
+    Many(Vec<FlagRuleConfig>),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FlagRuleConfig {
+    Exact(String),
+    Typed {
+        #[serde(rename = "type", default)]
+        kind: FlagKind,
+        value: String,
+        #[serde(default)]
+        trim: bool,
+    },
+}
+
+impl<'de> Deserialize<'de> for FlagSpec {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let rules = match FlagSpecConfig::deserialize(deserializer)? {
+            FlagSpecConfig::One(rule) => vec![rule],
+            FlagSpecConfig::Many(rules) => rules,
+        };
+        if rules.is_empty() {
+            return Err(serde::de::Error::custom(
+                "a challenge needs at least one flag",
+            ));
+        }
+        rules
+            .into_iter()
+            .map(|rule| {
+                let (kind, value, trim) = match rule {
+                    FlagRuleConfig::Exact(value) => (FlagKind::Static, value, false),
+                    FlagRuleConfig::Typed { kind, value, trim } => (kind, value, trim),
+                };
+                let (value, regex) = match kind {
+                    FlagKind::Static => (value, None),
+                    FlagKind::StaticCi => (value.to_lowercase(), None),
+                    FlagKind::Dynamic => (value, None),
+                    FlagKind::Regex => {
+                        let pattern = format!("^(?:{value})$");
+                        let regex = Regex::new(&pattern).map_err(serde::de::Error::custom)?;
+                        (pattern, Some(regex))
+                    }
+                };
+                Ok(FlagRule {
+                    kind,
+                    value,
+                    regex,
+                    trim,
+                })
+            })
+            .collect::<Result<_, _>>()
+            .map(FlagSpec)
+    }
+}
+
+impl FlagSpec {
+    // every rule is checked, so the response time doesn't tell which one matched
+    fn accepts(&self, submitted: &str, challenge_id: u16, username: &str) -> bool {
+        self.0.iter().fold(false, |accepted, rule| {
+            rule.accepts(submitted, challenge_id, username) | accepted
+        })
+    }
+
+    // the flag shown to this player, if the challenge has a dynamic one
+    fn dynamic_flag(&self, challenge_id: u16, username: &str) -> Option<String> {
+        self.0
+            .iter()
+            .find(|rule| matches!(rule.kind, FlagKind::Dynamic))
+            .map(|rule| dynamic_flag(&rule.value, challenge_id, username))
+    }
+
+    // every flag of this player, one per dynamic rule
+    fn dynamic_flags<'a>(
+        &'a self,
+        challenge_id: u16,
+        username: &'a str,
+    ) -> impl Iterator<Item = String> + 'a {
+        self.0
+            .iter()
+            .filter(|rule| matches!(rule.kind, FlagKind::Dynamic))
+            .map(move |rule| dynamic_flag(&rule.value, challenge_id, username))
+    }
+}
+
+impl FlagRule {
+    fn accepts(&self, submitted: &str, challenge_id: u16, username: &str) -> bool {
+        let submitted = if self.trim {
+            submitted.trim()
+        } else {
+            submitted
+        };
+        match self.kind {
+            FlagKind::Static => ct_eq(&self.value, submitted),
+            FlagKind::StaticCi => ct_eq(&self.value, &submitted.to_lowercase()),
+            FlagKind::Regex => self.regex.as_ref().unwrap().is_match(submitted),
+            FlagKind::Dynamic => ct_eq(
+                &dynamic_flag(&self.value, challenge_id, username),
+                submitted,
+            ),
+        }
+    }
+}
+
+// prefix{first 128 bits of HMAC-SHA256(secret, "challenge_id:username")}, the secret lives in
+// the database so the flags survive restarts
+fn dynamic_flag(prefix: &str, challenge_id: u16, username: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(FLAG_SECRET.get().unwrap().as_bytes()).unwrap();
+    mac.update(format!("{challenge_id}:{username}").as_bytes());
+    let digest = mac.finalize().into_bytes();
+    format!("{prefix}{{{}}}", HEXLOWER.encode(&digest[..16]))
 }
 
 #[derive(Deserialize, Debug, Serialize)]
@@ -60,16 +475,40 @@ struct ChallengeCategory {
     challenges: Vec<Challenge>,
 }
 
-#[derive(Deserialize, Debug, Serialize)]
+// what /challenges shows, points is the current value
+#[derive(Debug, Serialize)]
 struct ChallengeNoFlag {
     id: u16,
     name: String,
     description: String,
-    hint: Option<String>,
+    hints: Vec<PublicHint>,
     points: u32,
+    trusted_html: bool,
+    // username of the first solver with a first blood bonus
+    first_blood: Option<String>,
 }
 
-#[derive(Deserialize, Debug, Serialize)]
+// what /author/challenges shows, for the challenge authors
+#[derive(Debug, Serialize)]
+struct ChallengeStats<'a> {
+    id: u16,
+    name: &'a str,
+    category: &'a str,
+    points: u32,
+    solves: u32,
+    first_blood: Option<&'a String>,
+    // per hint, how many users unlocked it. free hints are always 0
+    hint_unlocks: Vec<u32>,
+}
+
+// the text of paid hints only comes from /hint_unlock
+#[derive(Debug, Serialize)]
+struct PublicHint {
+    cost: u32,
+    text: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
 struct ChallengeNoFlagCategory {
     name: String,
     challenges: Vec<ChallengeNoFlag>,
@@ -79,14 +518,126 @@ fn initialize_challenges() -> Result<(), Box<dyn Error>> {
     println!("Initializing challenges from config");
     let challs_str = read_to_string("./challenges.json")?;
     let challs: Vec<ChallengeCategory> = serde_json::from_str(&challs_str)?;
+    for chal in challs.iter().flat_map(|chal_cat| &chal_cat.challenges) {
+        if let Some(err) = chal.scoring.as_ref().and_then(Scoring::config_error) {
+            return Err(format!("challenge {} ({}): {err}", chal.id, chal.name).into());
+        }
+    }
     CHALLENGES_JSON.set(challs).unwrap();
-    let challs_no_flag: Vec<ChallengeNoFlagCategory> = serde_json::from_str(&challs_str)?;
-    CHALLENGES
-        .set(serde_json::to_string(&challs_no_flag)?)
-        .unwrap();
     Ok(())
 }
 
+// the public list, with the current values of the challenges
+fn refresh_challenges_cache(db: &DBInner) -> Result<(), Box<dyn Error>> {
+    let solve_counts = db._solve_counts();
+    let first_bloods = db._first_bloods();
+    let challs_no_flag: Vec<ChallengeNoFlagCategory> = CHALLENGES_JSON
+        .get()
+        .unwrap()
+        .iter()
+        .map(|chall_cat| ChallengeNoFlagCategory {
+            name: chall_cat.name.clone(),
+            challenges: chall_cat
+                .challenges
+                .iter()
+                .map(|chall| ChallengeNoFlag {
+                    id: chall.id,
+                    name: chall.name.clone(),
+                    description: chall.description.clone(),
+                    hints: chall
+                        .hints
+                        .iter()
+                        .map(|hint| PublicHint {
+                            cost: hint.cost,
+                            text: (hint.cost == 0).then(|| hint.text.clone()),
+                        })
+                        .collect(),
+                    points: chall.current_value(&solve_counts),
+                    trusted_html: chall.trusted_html,
+                    first_blood: first_bloods.get(&chall.id).cloned(),
+                })
+                .collect(),
+        })
+        .collect();
+    *CHALLENGES.lock().unwrap() = serde_json::to_string(&challs_no_flag)?;
+    Ok(())
+}
+
+fn find_challenge(id: u16) -> Option<&'static Challenge> {
+    CHALLENGES_JSON
+        .get()
+        .unwrap()
+        .iter()
+        .flat_map(|chal_cat| &chal_cat.challenges)
+        .find(|chal| chal.id == id)
+}
+
+// challenge values may have changed in the config since the last run
+fn initialize_scores(db: &mut DB) -> Result<(), Box<dyn Error>> {
+    println!("Recalculating scores");
+    if db.db._recalculate_scores() {
+        db.save()?;
+    }
+    Ok(())
+}
+
+fn initialize_security_headers() -> Result<(), Box<dyn Error>> {
+    println!("Initializing security headers");
+    let mut map = HeaderMap::new();
+    for (name, value) in SECURITY_HEADERS {
+        map.insert(
+            HeaderName::from_bytes(name.as_bytes())?,
+            HeaderValue::from_str(value)?,
+        );
+    }
+    if let Some(max_age) = HSTS_MAX_AGE {
+        map.insert(
+            STRICT_TRANSPORT_SECURITY,
+            HeaderValue::from_str(&format!("max-age={max_age}; includeSubDomains"))?,
+        );
+    }
+    SECURITY_HEADER_MAP.set(map).unwrap();
+    Ok(())
+}
+
+// None when CORS is turned off, so same-origin deployments skip the layer entirely
+fn cors_layer() -> Result<Option<CorsLayer>, Box<dyn Error>> {
+    if CORS_ALLOWED_ORIGINS.is_empty() {
+        return Ok(None);
+    }
+
+    let origins = if CORS_ALLOWED_ORIGINS == ["*"] {
+        if CORS_ALLOW_CREDENTIALS {
+            return Err("CORS_ALLOW_CREDENTIALS can't be combined with any origin".into());
+        }
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(
+            CORS_ALLOWED_ORIGINS
+                .iter()
+                .map(|origin| HeaderValue::from_str(origin))
+                .collect::<Result<Vec<_>, _>>()?,
+        )
+    };
+    let methods = CORS_ALLOWED_METHODS
+        .iter()
+        .map(|method| Method::from_bytes(method.as_bytes()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let headers = CORS_ALLOWED_HEADERS
+        .iter()
+        .map(|header| HeaderName::from_bytes(header.as_bytes()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(Some(
+        CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods(methods)
+            .allow_headers(headers)
+            .allow_credentials(CORS_ALLOW_CREDENTIALS)
+            .max_age(Duration::from_secs(CORS_MAX_AGE)),
+    ))
+}
+
 fn initialize_scoreboard_cache(users: &BTreeSet<UserScoreboard>) -> Result<(), Box<dyn Error>> {
     println!("Initializing scoreboard cache");
     *SCOREBOARD_CACHE.lock().unwrap() = serde_json::to_string(users)?;
@@ -116,6 +667,100 @@ fn get_timestamp() -> String {
     )
 }
 
+// generated on the first start, changing it changes every dynamic flag
+fn initialize_flag_secret(db: &mut DB) -> Result<(), Box<dyn Error>> {
+    if db.db.dynamic_flag_secret.is_empty() {
+        db.db.dynamic_flag_secret = generate_token();
+        db.save()?;
+    }
+    FLAG_SECRET.set(db.db.dynamic_flag_secret.clone()).unwrap();
+    Ok(())
+}
+
+fn initialize_dynamic_flag_owners(db: &DB) {
+    println!("Initializing dynamic flag owners");
+    for username in db.db.map.keys() {
+        add_dynamic_flag_owner(username);
+    }
+}
+
+// computes the flags of a new user once, instead of on every wrong submission
+fn add_dynamic_flag_owner(username: &str) {
+    // the whole map is built once the secret is known, see initialize_dynamic_flag_owners()
+    if FLAG_SECRET.get().is_none() {
+        return;
+    }
+    let mut owners = DYNAMIC_FLAG_OWNERS.lock().unwrap();
+    for chal in CHALLENGES_JSON
+        .get()
+        .into_iter()
+        .flatten()
+        .flat_map(|c| &c.challenges)
+    {
+        for flag in chal.flag.dynamic_flags(chal.id, username) {
+            owners.insert((chal.id, flag), username.to_string());
+        }
+    }
+}
+
+// whose dynamic flag got submitted, only asked after accepts() said no
+fn dynamic_flag_owner(challenge_id: u16, submitted: &str) -> Option<String> {
+    DYNAMIC_FLAG_OWNERS
+        .lock()
+        .unwrap()
+        .get(&(challenge_id, submitted.trim().to_string()))
+        .cloned()
+}
+
+fn initialize_admins(db: &mut DB) -> Result<(), Box<dyn Error>> {
+    for username in ADMIN_USERS {
+        match db.get(username) {
+            Some(u) if u.role == Role::Admin => {}
+            Some(u) => {
+                println!("Promoting {BLUE}{username}{RESET} to admin");
+                let before = u.role;
+                db.set_role(username, Role::Admin)?;
+                AuditEntry::new("(config)", None, "set_role", "user", username)
+                    .before(before.name())
+                    .after(Role::Admin.name())
+                    .append()?;
+            }
+            None => println!(
+                "Admin user {BLUE}{username}{RESET} doesn't exist yet, register it and restart"
+            ),
+        }
+    }
+    Ok(())
+}
+
+// `set-role <username> <player|author|admin>`, changes the role without starting the server
+fn set_role_command(db: &mut DB, args: &[String]) -> Result<(), Box<dyn Error>> {
+    let (username, role) = match (args.get(2), args.get(3).and_then(|r| Role::from_name(r))) {
+        (Some(username), Some(role)) => (username, role),
+        _ => {
+            return Err(format!(
+                "usage: {} set-role <username> <player|author|admin>",
+                args[0]
+            )
+            .into())
+        }
+    };
+    let before = match db.get(username) {
+        Some(u) => u.role,
+        None => return Err(format!("user {username} doesn't exist").into()),
+    };
+    db.set_role(username, role)?;
+    AuditEntry::new("(cli)", None, "set_role", "user", username)
+        .before(before.name())
+        .after(role.name())
+        .append()?;
+    println!(
+        "Role of {BLUE}{username}{RESET} set to {GOLD}{}{RESET}",
+        role.name()
+    );
+    Ok(())
+}
+
 async fn log_requests(
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     req: Request<Body>,
@@ -126,134 +771,582 @@ async fn log_requests(
         get_timestamp(),
         addr.ip(),
         req.method(),
-        req.uri()
+        redact_uri(req.uri())
     );
 
     next.run(req).await
 }
 
-fn get_auth_key(pass_hash: &str) -> String {
-    hash((AUTH_SECRET.to_owned() + pass_hash).as_bytes())
-        .to_hex()
-        .to_string()
+async fn security_headers(req: Request<Body>, next: Next<Body>) -> impl IntoResponse {
+    let mut res = next.run(req).await;
+    for (name, value) in SECURITY_HEADER_MAP.get().unwrap() {
+        res.headers_mut().insert(name, value.clone());
+    }
+
+    res
+}
+
+fn hash_password(password: &str) -> String {
+    let mut salt = [0u8; 16];
+    getrandom::getrandom(&mut salt).expect("Failed to generate password salt");
+    let config = argon2::Config {
+        variant: argon2::Variant::Argon2id,
+        mem_cost: ARGON2_MEM_COST,
+        time_cost: ARGON2_TIME_COST,
+        lanes: ARGON2_LANES,
+        ..argon2::Config::default()
+    };
+    // PHC string format, the salt and parameters are stored alongside the hash
+    argon2::hash_encoded(password.as_bytes(), &salt, &config).unwrap()
+}
+
+// equality for flags, tokens and other secrets. both sides are hashed first and blake3::Hash
+// compares in constant time, so neither the position of the first wrong byte nor the length
+// of a guess shows up in the response time
+fn ct_eq(a: &str, b: &str) -> bool {
+    hash(a.as_bytes()) == hash(b.as_bytes())
+}
+
+// accounts registered before the switch to argon2 store a bare blake3 hex digest
+fn is_legacy_hash(pass_hash: &str) -> bool {
+    !pass_hash.starts_with("$argon2")
+}
+
+fn verify_password(password: &str, pass_hash: &str) -> bool {
+    if is_legacy_hash(pass_hash) {
+        ct_eq(hash(password.as_bytes()).to_hex().as_str(), pass_hash)
+    } else {
+        argon2::verify_encoded(pass_hash, password.as_bytes()).unwrap_or(false)
+    }
+}
+
+// argon2 is slow on purpose, so it runs on the blocking pool and never while the DB is locked
+async fn verify_password_blocking(password: &str, pass_hash: String) -> bool {
+    let password = password.to_owned();
+    tokio::task::spawn_blocking(move || verify_password(&password, &pass_hash))
+        .await
+        .unwrap()
+}
+
+async fn hash_password_blocking(password: &str) -> String {
+    let password = password.to_owned();
+    tokio::task::spawn_blocking(move || hash_password(&password))
+        .await
+        .unwrap()
+}
+
+fn get_unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+fn format_duration(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{secs} seconds"),
+        60..=3599 => format!("{} minutes", secs.div_ceil(60)),
+        _ => format!("{} hours", secs.div_ceil(60 * 60)),
+    }
+}
+
+fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes).expect("Failed to generate token");
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn generate_recovery_code() -> String {
+    let mut bytes = [0u8; 5];
+    getrandom::getrandom(&mut bytes).unwrap();
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn totp_code(secret: &[u8], step: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).unwrap();
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    // dynamic truncation from RFC 4226
+    let offset = (digest[19] & 0x0f) as usize;
+    let binary = u32::from_be_bytes(digest[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+    binary % 10u32.pow(TOTP_DIGITS)
+}
+
+// returns the matching time step, steps at or before the last used one are rejected as replays
+fn check_totp(totp: &Totp, code: &str) -> Option<u64> {
+    let code = code.trim();
+    if code.len() != TOTP_DIGITS as usize {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let secret = BASE32_NOPAD.decode(totp.secret.as_bytes()).ok()?;
+    let now = get_unix_time() / TOTP_STEP;
+    (now.saturating_sub(TOTP_SKEW)..=now + TOTP_SKEW)
+        .find(|&step| step > totp.last_step && totp_code(&secret, step) == code)
+}
+
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+fn totp_uri(username: &str, secret: &str) -> String {
+    let issuer = percent_encode(TOTP_ISSUER);
+    format!(
+        "otpauth://totp/{issuer}:{}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={TOTP_DIGITS}&period={TOTP_STEP}",
+        percent_encode(username)
+    )
+}
+
+fn qr_svg(data: &str) -> String {
+    QrCode::new(data)
+        .unwrap()
+        .render::<svg::Color>()
+        .min_dimensions(200, 200)
+        .build()
+}
+
+fn get_bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+// only a hash of the token is kept server-side so a leaked database.db can't be used to log in
+fn hash_token(token: &str) -> String {
+    hash(token.as_bytes()).to_hex().to_string()
 }
 
 async fn flag_submit(
     State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Json(submition): Json<FlagSubmition>,
-) -> &'static str {
-    // need to be logged in
-    if submition.username.is_empty() || submition.auth_key.is_empty() {
-        return "{\"status\":\"FAIL\",\"cause\":\"You need to be logged in to submit flags\"}";
+) -> Result<&'static str, (StatusCode, &'static str)> {
+    // need to be logged in, or use an API token
+    if get_bearer_token(&headers).is_none()
+        && (submition.username.is_empty() || submition.auth_key.is_empty())
+    {
+        return Ok("{\"status\":\"FAIL\",\"cause\":\"You need to be logged in to submit flags\"}");
     }
 
+    // check authentication
     let mut db = state.database.lock().unwrap();
-    let db_user = match db.get(&submition.username) {
-        Some(ok) => ok,
-        None => {
-            return "{\"status\":\"FAIL\",\"cause\":\"User does not exist\"}";
+    let username = match db.authenticate(
+        &headers,
+        &submition.username,
+        &submition.auth_key,
+        ApiScope::Submit,
+    ) {
+        Ok(username) => username,
+        Err(e) => {
+            log_stdout(format!(
+                "Flag submit attempt {RED}failed{RESET} with: {BLUE}{}{RESET} [username: {}]",
+                e.cause(),
+                submition.username
+            ));
+            return Ok(e.json());
         }
     };
 
-    // check authentication
-    if get_auth_key(&db_user.password) != submition.auth_key {
+    // no flag bruteforcing
+    if !state
+        .flag_limiter
+        .check(&[format!("ip:{}", addr.ip()), format!("user:{username}")])
+    {
         log_stdout(format!(
-            "Flag submit attempt {RED}failed{RESET} with: {BLUE}Authentication failed: {submition:?}{RESET} [username: {}]",
-            submition.username
+            "Flag submit attempt {RED}throttled{RESET} [ip: {}] [username: {username}]",
+            addr.ip()
+        ));
+        return Err((
+            StatusCode::TOO_MANY_REQUESTS,
+            "{\"status\":\"FAIL\",\"cause\":\"Too many flag submissions, slow down\"}",
         ));
-        return "{\"status\":\"FAIL\",\"cause\":\"Authentication failed\"}";
     }
 
+    let db_user = match db.get(&username) {
+        Some(ok) => ok,
+        None => {
+            return Ok("{\"status\":\"FAIL\",\"cause\":\"User does not exist\"}");
+        }
+    };
+
     // already solved the challenge
     if db_user.solves.contains(&submition.challenge_id) {
-        return "{\"status\":\"FAIL\",\"cause\":\"You have already solved this challenge\"}";
+        return Ok("{\"status\":\"FAIL\",\"cause\":\"You have already solved this challenge\"}");
     }
 
-    let mut points: Option<u32> = None;
+    let mut found = false;
     for chal_cat in CHALLENGES_JSON.get().unwrap() {
         for chall in &chal_cat.challenges {
             if chall.id == submition.challenge_id {
-                points = Some(chall.points);
-                if chall.flag != submition.flag {
+                found = true;
+                if !chall.flag.accepts(&submition.flag, chall.id, &username) {
                     log_stdout(format!(
                         "Flag submit attempt {RED}failed{RESET} with: {BLUE}Wrong flag ({}):{}{RESET} [username: {}]",
-                        submition.challenge_id, submition.flag, submition.username
+                        submition.challenge_id,
+                        Sensitive(&submition.flag, LOG_FLAGS),
+                        username
                     ));
-                    return "{\"status\":\"FAIL\",\"cause\":\"Wrong flag\"}";
+                    // the player still only sees "Wrong flag", admins get the incident
+                    if let Some(owner) = dynamic_flag_owner(chall.id, &submition.flag) {
+                        log_stdout(format!(
+                            "Flag submit of ({}) by {BLUE}{username}{RESET} is the {RED}shared flag{RESET} of {BLUE}{owner}{RESET} [ip: {}]",
+                            submition.challenge_id,
+                            addr.ip()
+                        ));
+                        db.record_sharing_incident(chall.id, &username, &owner, addr.ip())
+                            .unwrap();
+                    }
+                    return Ok("{\"status\":\"FAIL\",\"cause\":\"Wrong flag\"}");
                 }
             }
         }
     }
 
-    if points.is_none() {
-        return "{\"status\":\"FAIL\",\"cause\":\"Challenge doesnt exist\"}";
+    if !found {
+        return Ok("{\"status\":\"FAIL\",\"cause\":\"Challenge doesnt exist\"}");
     }
 
     log_stdout(format!(
         "Flag submit attempt {GOLD}success{RESET}: {BLUE}({}):{}{RESET} [username: {}]",
-        submition.challenge_id, submition.flag, submition.username
+        submition.challenge_id,
+        Sensitive(&submition.flag, LOG_FLAGS),
+        username
     ));
 
-    let mut u = db_user.clone();
-    u.solves.push(submition.challenge_id);
-    u.score += points.unwrap();
-    db.set(submition.username, u).unwrap();
+    if let Some(bonus) = db.add_solve(&username, submition.challenge_id).unwrap() {
+        log_stdout(format!(
+            "{RED}{}{RESET} of ({}) for {BLUE}{username}{RESET} [bonus: {GOLD}+{}{RESET}]",
+            bonus.title(),
+            bonus.challenge_id,
+            bonus.points
+        ));
+    }
 
-    "{\"status\":\"OK\"}"
+    Ok("{\"status\":\"OK\"}")
 }
 
-async fn challenges() -> &'static str {
+async fn challenges() -> String {
     if CTF_STARTED {
-        CHALLENGES.get().unwrap()
+        CHALLENGES.lock().unwrap().to_string()
     } else {
-        "{}"
+        "{}".to_string()
     }
 }
 
-async fn scoreboard() -> String {
-    // TODO: return only first X records for better performance
-    SCOREBOARD_CACHE.lock().unwrap().to_string()
+// the player's own flags of the dynamic challenges, for the {{flag}} in their descriptions
+async fn dynamic_flags(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(user): Json<ProfileRequest>,
+) -> String {
+    if !CTF_STARTED {
+        return "{\"status\":\"OK\",\"flags\":{}}".to_string();
+    }
+    let mut db = state.database.lock().unwrap();
+    let username = match db.authenticate(&headers, &user.username, &user.auth_key, ApiScope::Read) {
+        Ok(username) => username,
+        Err(e) => return e.json().to_string(),
+    };
+    let flags: HashMap<u16, String> = CHALLENGES_JSON
+        .get()
+        .unwrap()
+        .iter()
+        .flat_map(|chal_cat| &chal_cat.challenges)
+        .filter_map(|chall| Some((chall.id, chall.flag.dynamic_flag(chall.id, &username)?)))
+        .collect();
+    format!(
+        "{{\"status\":\"OK\",\"flags\":{}}}",
+        serde_json::to_string(&flags).unwrap()
+    )
 }
 
-async fn register(
+// returns the hint text, the cost is charged only the first time
+async fn hint_unlock(
     State(state): State<Arc<AppState>>,
-    Json(user): Json<UserRegister>,
-) -> &'static str {
-    // all fields must not by empty (except confirm_password as that is checked later anyway)
-    if user.username.is_empty() || user.password.is_empty() || user.email.is_empty() {
-        return "{\"status\":\"FAIL\",\"cause\":\"Fields must not be empty\"}";
+    headers: HeaderMap,
+    Json(req): Json<HintUnlockRequest>,
+) -> String {
+    if !CTF_STARTED {
+        return "{\"status\":\"FAIL\",\"cause\":\"CTF hasn't started yet\"}".to_string();
     }
-
-    // username already registered
-    if state.database.lock().unwrap().get(&user.username).is_some() {
+    let mut db = state.database.lock().unwrap();
+    let username = match db.authenticate(&headers, &req.username, &req.auth_key, ApiScope::Submit) {
+        Ok(username) => username,
+        Err(e) => return e.json().to_string(),
+    };
+    let hint = match find_challenge(req.challenge_id).and_then(|chall| chall.hints.get(req.hint)) {
+        Some(hint) => hint,
+        None => {
+            return "{\"status\":\"FAIL\",\"cause\":\"Hint doesnt exist\"}".to_string();
+        }
+    };
+    let db_user = db.get(&username).unwrap();
+    if !db_user.has_hint(req.challenge_id, req.hint) {
+        if db_user.score < i64::from(hint.cost) {
+            return "{\"status\":\"FAIL\",\"cause\":\"Not enough points to unlock this hint\"}"
+                .to_string();
+        }
+        db.unlock_hint(
+            &username,
+            HintUnlock {
+                challenge_id: req.challenge_id,
+                hint: req.hint,
+                cost: hint.cost,
+            },
+        )
+        .unwrap();
         log_stdout(format!(
-            "Register attempt {RED}failed{RESET} with: {BLUE}Username already registered{RESET} [username: {}] [email: {}]",
-            user.username, user.email
+            "Hint {} of ({}) {GOLD}unlocked{RESET} by {BLUE}{username}{RESET} [cost: {}]",
+            req.hint + 1,
+            req.challenge_id,
+            hint.cost
         ));
-        return "{\"status\":\"FAIL\",\"cause\":\"Username already registered\"}";
     }
+    format!(
+        "{{\"status\":\"OK\",\"text\":{}}}",
+        serde_json::to_string(&hint.text).unwrap()
+    )
+}
 
-    // passwords do not match
-    if user.password != user.confirm_password {
-        log_stdout(format!(
-            "Register attempt {RED}failed{RESET} with: {BLUE}Passwords do not match{RESET} [username: {}] [email: {}]",
-            user.username, user.email
-        ));
-        return "{\"status\":\"FAIL\",\"cause\":\"Passwords do not match\"}";
-    }
+// the texts of the paid hints the player already unlocked
+async fn unlocked_hints(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(user): Json<ProfileRequest>,
+) -> String {
+    let mut db = state.database.lock().unwrap();
+    let username = match db.authenticate(&headers, &user.username, &user.auth_key, ApiScope::Read) {
+        Ok(username) => username,
+        Err(e) => return e.json().to_string(),
+    };
+    let hints: Vec<UnlockedHint> = db
+        .get(&username)
+        .unwrap()
+        .hint_unlocks
+        .iter()
+        .filter_map(|unlock| {
+            Some(UnlockedHint {
+                challenge_id: unlock.challenge_id,
+                hint: unlock.hint,
+                text: &find_challenge(unlock.challenge_id)?
+                    .hints
+                    .get(unlock.hint)?
+                    .text,
+            })
+        })
+        .collect();
+    format!(
+        "{{\"status\":\"OK\",\"hints\":{}}}",
+        serde_json::to_string(&hints).unwrap()
+    )
+}
 
-    // password wrong length
-    if user.password.len() < 4 || user.password.len() > 64 {
-        log_stdout(format!(
+async fn scoreboard() -> String {
+    // TODO: return only first X records for better performance
+    SCOREBOARD_CACHE.lock().unwrap().to_string()
+}
+
+// folds compatibility characters (fullwidth letters, ligatures, ...) into their plain form
+fn normalize_username(username: &str) -> String {
+    username.trim().nfkc().collect()
+}
+
+// why the (normalized) username can't be registered, if it can't
+fn username_error(username: &str) -> Option<String> {
+    let length = username.chars().count();
+    if !(USERNAME_MIN_LENGTH..=USERNAME_MAX_LENGTH).contains(&length) {
+        return Some(format!(
+            "Username length should be in the range: {USERNAME_MIN_LENGTH}-{USERNAME_MAX_LENGTH}"
+        ));
+    }
+    let allowed = |c: char| {
+        USERNAME_EXTRA_CHARS.contains(c)
+            || if USERNAME_ASCII_ONLY {
+                c.is_ascii_alphanumeric()
+            } else {
+                c.is_alphanumeric()
+            }
+    };
+    if !username.chars().all(allowed) {
+        return Some(format!(
+            "Username may only contain letters, digits and: {USERNAME_EXTRA_CHARS}"
+        ));
+    }
+    None
+}
+
+// UTS 39 skeleton, the same for names which look alike ("admin" and "\u{430}dmin")
+fn username_skeleton(username: &str) -> String {
+    skeleton(&username.to_lowercase()).collect()
+}
+
+// deliberately loose, whether mail arrives is the real test
+fn email_error(email: &str) -> Option<&'static str> {
+    let (local, domain) = match email.split_once('@') {
+        Some(parts) => parts,
+        None => return Some("Invalid e-mail address"),
+    };
+    // a second @ ends up in the domain, which only allows letters, digits and hyphens
+    let valid = email.len() <= 254
+        && !local.is_empty()
+        && local.len() <= 64
+        && !email
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || "<>()[]\\,;:\"".contains(c))
+        && domain.contains('.')
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_alphanumeric() || c == '-')
+        });
+    (!valid).then_some("Invalid e-mail address")
+}
+
+// why the registration mode refuses a new account, invite codes are checked against the DB
+fn registration_policy_error(email: &str) -> Option<&'static str> {
+    match REGISTRATION_MODE {
+        RegistrationMode::Open | RegistrationMode::InviteCode => None,
+        RegistrationMode::Closed => Some("Registration is closed"),
+        RegistrationMode::EmailDomains(_) => email_domain_error(email),
+    }
+}
+
+// existing accounts can't move to an address outside the allowed domains either
+fn email_domain_error(email: &str) -> Option<&'static str> {
+    match REGISTRATION_MODE {
+        RegistrationMode::EmailDomains(domains) => {
+            let domain = email.rsplit_once('@').map(|(_, d)| d).unwrap_or_default();
+            if domains.iter().any(|d| d.eq_ignore_ascii_case(domain)) {
+                None
+            } else {
+                Some("E-mail domain not allowed")
+            }
+        }
+        _ => None,
+    }
+}
+
+// identity provider accounts can't bring an invite code and only verified addresses count
+fn oidc_registration_error(claims: &IdTokenClaims) -> Option<&'static str> {
+    if matches!(REGISTRATION_MODE, RegistrationMode::InviteCode) {
+        return Some("Registration requires an invite code");
+    }
+    let email = claims.email.as_deref().filter(|_| claims.email_verified);
+    registration_policy_error(email.unwrap_or_default())
+}
+
+async fn register(
+    State(state): State<Arc<AppState>>,
+    Json(mut user): Json<UserRegister>,
+) -> String {
+    // all fields must not by empty (except confirm_password as that is checked later anyway)
+    if user.username.is_empty() || user.password.is_empty() || user.email.is_empty() {
+        return "{\"status\":\"FAIL\",\"cause\":\"Fields must not be empty\"}".to_string();
+    }
+    user.username = normalize_username(&user.username);
+    user.email = user.email.trim().to_string();
+
+    // registration mode of the event
+    if let Some(cause) = registration_policy_error(&user.email) {
+        log_stdout(format!(
+            "Register attempt {RED}failed{RESET} with: {BLUE}{cause}{RESET} [username: {}] [email: {}]",
+            user.username,
+            Sensitive(&user.email, LOG_EMAILS)
+        ));
+        return format!("{{\"status\":\"FAIL\",\"cause\":\"{cause}\"}}");
+    }
+
+    if let Some(cause) = username_error(&user.username) {
+        log_stdout(format!(
+            "Register attempt {RED}failed{RESET} with: {BLUE}{cause}{RESET} [username: {}] [email: {}]",
+            user.username,
+            Sensitive(&user.email, LOG_EMAILS)
+        ));
+        return format!(
+            "{{\"status\":\"FAIL\",\"cause\":{}}}",
+            serde_json::to_string(&cause).unwrap()
+        );
+    }
+
+    if let Some(cause) = email_error(&user.email) {
+        log_stdout(format!(
+            "Register attempt {RED}failed{RESET} with: {BLUE}{cause}{RESET} [username: {}] [email: {}]",
+            user.username,
+            Sensitive(&user.email, LOG_EMAILS)
+        ));
+        return format!("{{\"status\":\"FAIL\",\"cause\":\"{cause}\"}}");
+    }
+
+    // passwords do not match
+    if user.password != user.confirm_password {
+        log_stdout(format!(
+            "Register attempt {RED}failed{RESET} with: {BLUE}Passwords do not match{RESET} [username: {}] [email: {}]",
+            user.username,
+            Sensitive(&user.email, LOG_EMAILS)
+        ));
+        return "{\"status\":\"FAIL\",\"cause\":\"Passwords do not match\"}".to_string();
+    }
+
+    // password wrong length
+    if user.password.len() < 4 || user.password.len() > 64 {
+        log_stdout(format!(
             "Register attempt {RED}failed{RESET} with: {BLUE}Passwords wrong length{RESET} [username: {}] [email: {}]",
-            user.username, user.email
+            user.username,
+            Sensitive(&user.email, LOG_EMAILS)
         ));
-        return "{\"status\":\"FAIL\",\"cause\":\"Password length should be in the range: 4-64\"}";
+        return "{\"status\":\"FAIL\",\"cause\":\"Password length should be in the range: 4-64\"}"
+            .to_string();
+    }
+
+    // hashing before taking the lock, it's slow on purpose
+    let password = hash_password_blocking(&user.password).await;
+    let mut db = state.database.lock().unwrap();
+
+    // checked under the same lock as the insert, so two concurrent registrations can't both pass
+    let taken = match db.similar_username(&user.username) {
+        Some(existing) if existing == user.username => Some("Username already registered"),
+        Some(_) => Some("Username is too similar to an existing one"),
+        None if db.email_taken(&user.email, "") => Some("E-mail already registered"),
+        None => None,
+    };
+    if let Some(cause) = taken {
+        log_stdout(format!(
+            "Register attempt {RED}failed{RESET} with: {BLUE}{cause}{RESET} [username: {}] [email: {}]",
+            user.username,
+            Sensitive(&user.email, LOG_EMAILS)
+        ));
+        return format!("{{\"status\":\"FAIL\",\"cause\":\"{cause}\"}}");
+    }
+
+    // the invite code is only used up once everything else checked out
+    if matches!(REGISTRATION_MODE, RegistrationMode::InviteCode) {
+        if let Some(cause) = db.check_invite(&user.invite_code) {
+            log_stdout(format!(
+                "Register attempt {RED}failed{RESET} with: {BLUE}{cause}{RESET} [username: {}] [email: {}]",
+                user.username,
+                Sensitive(&user.email, LOG_EMAILS)
+            ));
+            return format!("{{\"status\":\"FAIL\",\"cause\":\"{cause}\"}}");
+        }
+        db.use_invite(&user.invite_code).unwrap();
     }
 
     log_stdout(format!(
         "Register attempt {GOLD}success{RESET} [username: {}] [email: {}]",
-        user.username, user.email
+        user.username,
+        Sensitive(&user.email, LOG_EMAILS)
     ));
 
     // actually register to DB
@@ -261,18 +1354,22 @@ async fn register(
         id: 0,
         username: user.username,
         email: user.email,
-        password: hash(user.password.as_bytes()).to_hex().to_string(),
+        password,
         score: 0,
         solves: Vec::new(),
+        bonuses: Vec::new(),
+        hint_unlocks: Vec::new(),
+        failed_logins: 0,
+        locked_until: 0,
+        lockouts: 0,
+        totp: None,
+        totp_required: false,
+        oidc_subject: None,
+        role: Role::Player,
     };
-    state
-        .database
-        .lock()
-        .unwrap()
-        .set(u.username.to_owned(), u)
-        .unwrap();
+    db.set(u.username.to_owned(), u).unwrap();
 
-    "{\"status\":\"OK\"}"
+    "{\"status\":\"OK\"}".to_string()
 }
 
 #[derive(Debug, Deserialize)]
@@ -281,6 +1378,262 @@ struct UserRegister {
     email: String,
     password: String,
     confirm_password: String,
+    #[serde(default)]
+    invite_code: String,
+}
+
+fn send_mail(to: &str, subject: &str, text: &str) -> Result<(), Box<dyn Error>> {
+    // the address ends up in the headers, so it must not be able to inject new ones
+    if to.contains(['\r', '\n']) || subject.contains(['\r', '\n']) {
+        return Err("Invalid mail header".into());
+    }
+    let mut message = format!(
+        "From: {MAIL_FROM}\r\nTo: {to}\r\nSubject: {subject}\r\nMessage-ID: <{}@hostctf>\r\nMIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n",
+        generate_token()
+    );
+    for line in text.lines() {
+        message.push_str(line);
+        message.push_str("\r\n");
+    }
+
+    match MAIL_TRANSPORT {
+        MailTransport::Directory(dir) => {
+            create_dir_all(dir)?;
+            let path = Path::new(dir).join(format!(
+                "{}-{}.eml",
+                get_unix_time(),
+                &generate_token()[..8]
+            ));
+            std::fs::write(path, message)?;
+        }
+        MailTransport::Smtp { host, port } => {
+            // a stuck relay must not keep the blocking thread forever
+            let timeout = Duration::from_secs(SMTP_TIMEOUT);
+            let addr = (host, port)
+                .to_socket_addrs()?
+                .next()
+                .ok_or("SMTP host not found")?;
+            let stream = TcpStream::connect_timeout(&addr, timeout)?;
+            stream.set_read_timeout(Some(timeout))?;
+            stream.set_write_timeout(Some(timeout))?;
+            let mut reader = BufReader::new(stream.try_clone()?);
+            let mut writer = stream;
+            smtp_expect(&mut reader, "220")?;
+            for (command, code) in [
+                ("HELO hostctf".to_string(), "250"),
+                (format!("MAIL FROM:<{MAIL_FROM}>"), "250"),
+                (format!("RCPT TO:<{to}>"), "250"),
+                ("DATA".to_string(), "354"),
+            ] {
+                writer.write_all(format!("{command}\r\n").as_bytes())?;
+                smtp_expect(&mut reader, code)?;
+            }
+            // lines starting with a dot have to be escaped, a lone dot ends the data
+            let message = message.replace("\r\n.", "\r\n..");
+            writer.write_all(format!("{message}.\r\n").as_bytes())?;
+            smtp_expect(&mut reader, "250")?;
+            writer.write_all(b"QUIT\r\n")?;
+        }
+    }
+    Ok(())
+}
+
+fn smtp_expect(reader: &mut impl BufRead, code: &str) -> Result<(), Box<dyn Error>> {
+    // multiline replies use "250-" for every line except the last one ("250 ")
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        if !line.starts_with(code) {
+            return Err(format!("Unexpected SMTP reply: {}", line.trim_end()).into());
+        }
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(());
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RateLimiter {
+    limit: RateLimit,
+    buckets: Mutex<HashMap<String, (f64, Instant)>>,
+}
+
+impl RateLimiter {
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // takes a token from the bucket of every key, nothing is taken if any of them is empty
+    fn check(&self, keys: &[String]) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        let now = Instant::now();
+        let burst = self.limit.burst as f64;
+        let refill = self.limit.per_minute as f64 / 60.0;
+
+        // forget the idle clients once in a while so the map doesn't grow forever
+        if buckets.len() > 10_000 {
+            buckets.retain(|_, (tokens, last)| {
+                *tokens + now.duration_since(*last).as_secs_f64() * refill < burst
+            });
+        }
+
+        for key in keys {
+            let (tokens, last) = buckets.entry(key.to_string()).or_insert((burst, now));
+            *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * refill).min(burst);
+            *last = now;
+        }
+        if keys.iter().any(|key| buckets[key].0 < 1.0) {
+            return false;
+        }
+        for key in keys {
+            buckets.get_mut(key).unwrap().0 -= 1.0;
+        }
+        true
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OidcProvider {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+}
+
+// an authorization request which waits for the user to come back
+#[derive(Debug)]
+struct OidcFlow {
+    nonce: String,
+    code_verifier: String,
+    expires: u64,
+    // set when a logged in user links the identity to their account
+    link_to: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OidcTokenResponse {
+    id_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OidcAudience {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Debug, Deserialize)]
+struct IdTokenClaims {
+    iss: String,
+    aud: OidcAudience,
+    exp: u64,
+    nonce: Option<String>,
+    sub: String,
+    email: Option<String>,
+    #[serde(default)]
+    email_verified: bool,
+    preferred_username: Option<String>,
+}
+
+fn oidc_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .unwrap()
+}
+
+async fn oidc_discover() -> Result<OidcProvider, Box<dyn Error + Send + Sync>> {
+    let provider: OidcProvider = oidc_client()
+        .get(format!(
+            "{}/.well-known/openid-configuration",
+            OIDC_ISSUER.trim_end_matches('/')
+        ))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    // the document has to describe the issuer we asked for (OIDC Discovery 4.3)
+    if provider.issuer != OIDC_ISSUER {
+        return Err(format!("discovery returned issuer {}", provider.issuer).into());
+    }
+    Ok(provider)
+}
+
+fn oidc_authorization_url(provider: &OidcProvider, state: &str, flow: &OidcFlow) -> String {
+    let code_challenge = BASE64URL_NOPAD.encode(&Sha256::digest(flow.code_verifier.as_bytes()));
+    let separator = if provider.authorization_endpoint.contains('?') {
+        '&'
+    } else {
+        '?'
+    };
+    format!(
+        "{}{separator}response_type=code&scope=openid%20email%20profile&client_id={}&redirect_uri={}&state={state}&nonce={}&code_challenge={code_challenge}&code_challenge_method=S256",
+        provider.authorization_endpoint,
+        percent_encode(OIDC_CLIENT_ID),
+        percent_encode(OIDC_REDIRECT_URL),
+        flow.nonce
+    )
+}
+
+// redeems the authorization code and returns the validated claims of the id token
+async fn oidc_exchange_code(
+    code: &str,
+    flow: &OidcFlow,
+) -> Result<IdTokenClaims, Box<dyn Error + Send + Sync>> {
+    let provider = oidc_discover().await?;
+    let mut params = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", OIDC_REDIRECT_URL),
+        ("client_id", OIDC_CLIENT_ID),
+        ("code_verifier", &flow.code_verifier),
+    ];
+    if !OIDC_CLIENT_SECRET.is_empty() {
+        params.push(("client_secret", OIDC_CLIENT_SECRET));
+    }
+    let tokens: OidcTokenResponse = oidc_client()
+        .post(&provider.token_endpoint)
+        .form(&params)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    // the token came directly from the token endpoint, which stands in
+    // for checking its signature (OIDC Core 3.1.3.7)
+    let payload = tokens
+        .id_token
+        .split('.')
+        .nth(1)
+        .ok_or("malformed id token")?;
+    let claims: IdTokenClaims =
+        serde_json::from_slice(&BASE64URL_NOPAD.decode(payload.as_bytes())?)?;
+
+    if claims.iss != provider.issuer {
+        return Err(format!("id token issued by {}", claims.iss).into());
+    }
+    let audience_ok = match &claims.aud {
+        OidcAudience::One(aud) => aud == OIDC_CLIENT_ID,
+        OidcAudience::Many(auds) => auds.iter().any(|aud| aud == OIDC_CLIENT_ID),
+    };
+    if !audience_ok {
+        return Err("id token issued for another client".into());
+    }
+    if claims.exp <= get_unix_time() {
+        return Err("id token expired".into());
+    }
+    if !claims
+        .nonce
+        .as_deref()
+        .is_some_and(|nonce| ct_eq(nonce, &flow.nonce))
+    {
+        return Err("id token nonce mismatch".into());
+    }
+    Ok(claims)
 }
 
 fn log_stdout(mes: String) {
@@ -289,89 +1642,1709 @@ fn log_stdout(mes: String) {
     }
 }
 
-async fn login(State(state): State<Arc<AppState>>, Json(user): Json<UserLogin>) -> String {
-    let db = state.database.lock().unwrap();
-    let db_user: &User = match db.get(&user.username) {
-        Some(ok) => ok,
-        None => {
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+enum Redaction {
+    Plain,
+    Mask, // only the first character, e-mails keep their domain: a***@example.edu
+    Hash, // keyed with a random key per run, so equal values still match up but can't be guessed
+    Hidden,
+}
+
+// marks a log field, which is written out according to its redaction setting
+struct Sensitive<'a>(&'a str, Redaction);
+
+impl fmt::Display for Sensitive<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Sensitive(value, redaction) = *self;
+        match redaction {
+            Redaction::Plain => f.write_str(value),
+            Redaction::Mask => {
+                let (local, domain) = match value.split_once('@') {
+                    Some((local, domain)) => (local, Some(domain)),
+                    None => (value, None),
+                };
+                if let Some(c) = local.chars().next() {
+                    write!(f, "{c}")?;
+                }
+                f.write_str("***")?;
+                if let Some(domain) = domain {
+                    write!(f, "@{domain}")?;
+                }
+                Ok(())
+            }
+            Redaction::Hash => {
+                let key = LOG_HASH_KEY.get_or_init(|| {
+                    let mut key = [0u8; 32];
+                    getrandom::getrandom(&mut key).expect("Failed to generate log hash key");
+                    key
+                });
+                let digest = blake3::keyed_hash(key, value.as_bytes());
+                write!(f, "#{}", &digest.to_hex()[..12])
+            }
+            Redaction::Hidden => f.write_str("[redacted]"),
+        }
+    }
+}
+
+// the request url as logged, with the values of sensitive query parameters redacted
+fn redact_uri(uri: &Uri) -> String {
+    let query = match uri.query() {
+        Some(query) => query,
+        None => return uri.to_string(),
+    };
+    let query = query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((name, value)) if SENSITIVE_QUERY_PARAMS.contains(&name) => {
+                format!("{name}={}", Sensitive(value, LOG_QUERY_SECRETS))
+            }
+            _ => pair.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+    format!("{}?{query}", uri.path())
+}
+
+async fn login(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(mut user): Json<UserLogin>,
+) -> Result<String, (StatusCode, &'static str)> {
+    // accounts are stored under the NFKC form, see register
+    user.username = normalize_username(&user.username);
+
+    // no password bruteforcing
+    if !state.login_limiter.check(&[
+        format!("ip:{}", addr.ip()),
+        format!("user:{}", user.username),
+    ]) {
+        log_stdout(format!(
+            "Login attempt {RED}throttled{RESET} [ip: {}] [username: {}]",
+            addr.ip(),
+            user.username
+        ));
+        return Err((
+            StatusCode::TOO_MANY_REQUESTS,
+            "{\"status\":\"FAIL\",\"cause\":\"Too many login attempts, try again later\"}",
+        ));
+    }
+
+    let pass_hash = {
+        let db = state.database.lock().unwrap();
+        let db_user: &User = match db.get(&user.username) {
+            Some(ok) => ok,
+            None => {
+                log_stdout(format!(
+                    "Login attempt {RED}failed{RESET} with: {BLUE}User not found{RESET} [username: {}]",
+                    user.username
+                ));
+                return Ok("{\"status\":\"FAIL\",\"cause\":\"User not found\"}".to_string());
+            }
+        };
+
+        // locked accounts don't get to try passwords at all
+        let now = get_unix_time();
+        if db_user.locked_until > now {
             log_stdout(format!(
-                "Login attempt {RED}failed{RESET} with: {BLUE}User not found{RESET} [username: {}]",
+                "Login attempt {RED}failed{RESET} with: {BLUE}Account locked{RESET} [username: {}]",
                 user.username
             ));
-            return "{\"status\":\"FAIL\",\"cause\":\"User not found\"}".to_string();
+            return Ok(format!(
+                "{{\"status\":\"FAIL\",\"cause\":\"Account locked, try again in {}\"}}",
+                format_duration(db_user.locked_until - now)
+            ));
         }
+        db_user.password.to_owned()
     };
 
     // correct password
-    if hash(user.password.as_bytes()).to_hex().to_string() != db_user.password {
+    let password_ok = verify_password_blocking(&user.password, pass_hash.to_owned()).await;
+    // transparently migrate legacy blake3 hashes now that we know the plaintext
+    let migrated_hash = if password_ok && is_legacy_hash(&pass_hash) {
+        Some(hash_password_blocking(&user.password).await)
+    } else {
+        None
+    };
+
+    let mut db = state.database.lock().unwrap();
+    let db_user: &User = match db.get(&user.username) {
+        Some(ok) => ok,
+        None => {
+            return Ok("{\"status\":\"FAIL\",\"cause\":\"User not found\"}".to_string());
+        }
+    };
+    if !password_ok {
         log_stdout(format!(
             "Login attempt {RED}failed{RESET} with: {BLUE}Wrong password{RESET} [username: {}]",
             user.username
         ));
-        return "{\"status\":\"FAIL\",\"cause\":\"Wrong password\"}".to_string();
+        if let Some(duration) = db.record_failed_login(&user.username).unwrap() {
+            log_stdout(format!(
+                "Account {RED}locked{RESET} for {} [username: {}]",
+                format_duration(duration),
+                user.username
+            ));
+            state.drop_pending_logins(&user.username);
+            return Ok(format!(
+                "{{\"status\":\"FAIL\",\"cause\":\"Wrong password, account locked for {}\"}}",
+                format_duration(duration)
+            ));
+        }
+        return Ok("{\"status\":\"FAIL\",\"cause\":\"Wrong password\"}".to_string());
+    }
+
+    // second step, the client repeats the request with the code from the authenticator app
+    if db_user.totp.as_ref().is_some_and(|t| t.enabled) {
+        if user.totp_code.is_empty() {
+            return Ok("{\"status\":\"2fa_required\"}".to_string());
+        }
+        if !db
+            .verify_second_factor(&user.username, &user.totp_code)
+            .unwrap()
+        {
+            log_stdout(format!(
+                "Login attempt {RED}failed{RESET} with: {BLUE}Wrong two-factor code{RESET} [username: {}]",
+                user.username
+            ));
+            if let Some(duration) = db.record_failed_login(&user.username).unwrap() {
+                log_stdout(format!(
+                    "Account {RED}locked{RESET} for {} [username: {}]",
+                    format_duration(duration),
+                    user.username
+                ));
+                state.drop_pending_logins(&user.username);
+            }
+            return Ok("{\"status\":\"FAIL\",\"cause\":\"Wrong two-factor code\"}".to_string());
+        }
+    }
+    let db_user = db.get(&user.username).unwrap();
+
+    // unless the password changed while the lock was released
+    if let Some(new_hash) = migrated_hash.filter(|_| db_user.password == pass_hash) {
+        log_stdout(format!(
+            "Migrating password hash to {BLUE}argon2id{RESET} [username: {}]",
+            user.username
+        ));
+        let mut u = db_user.clone();
+        u.password = new_hash;
+        db.set(user.username.to_owned(), u).unwrap();
     }
+    // a successful login ends the streak of failures
+    db.clear_lockout(&user.username).unwrap();
 
     log_stdout(format!(
         "Login attempt {GOLD}success{RESET} [username: {}]",
         user.username
     ));
 
-    let auth_key = get_auth_key(&db_user.password);
+    let db_user = db.get(&user.username).unwrap();
     let solved_chals = serde_json::to_string(&db_user.solves).unwrap();
+    let totp_setup_required = db_user.needs_totp_setup();
+    let auth_key = db.create_session(&user.username, addr.ip()).unwrap();
 
-    format!("{{\"status\":\"OK\",\"auth_key\":\"{auth_key}\",\"solved_chals\":{solved_chals}}}")
+    Ok(format!(
+        "{{\"status\":\"OK\",\"auth_key\":\"{auth_key}\",\"solved_chals\":{solved_chals},\"totp_setup_required\":{totp_setup_required}}}"
+    ))
 }
 
 #[derive(Debug, Deserialize)]
 struct UserLogin {
     username: String,
     password: String,
+    // only needed by accounts with two-factor authentication
+    #[serde(default)]
+    totp_code: String,
 }
 
 #[derive(Debug, Deserialize)]
 struct ProfileRequest {
+    // not needed with an API token
+    #[serde(default)]
     username: String,
+    #[serde(default)]
+    auth_key: String,
 }
 
-async fn profile(State(state): State<Arc<AppState>>, Json(user): Json<ProfileRequest>) -> String {
-    let db = state.database.lock().unwrap();
-    let db_user = match db.get(&user.username) {
+async fn profile(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(user): Json<ProfileRequest>,
+) -> String {
+    let mut db = state.database.lock().unwrap();
+    let username = match db.authenticate(&headers, &user.username, &user.auth_key, ApiScope::Read) {
+        Ok(username) => username,
+        Err(e) => return e.json().to_string(),
+    };
+    let db_user = match db.get(&username) {
         Some(ok) => ok,
         None => {
             return "{\"status\":\"FAIL\",\"cause\":\"User doesnt exist\"}".to_string();
         }
     };
-    format!("{{\"status\":\"OK\",\"score\":{}}}", db_user.score)
+    format!(
+        "{{\"status\":\"OK\",\"username\":{},\"role\":\"{}\",\"score\":{},\"hint_costs\":{},\"totp_enabled\":{},\"oidc_linked\":{}}}",
+        serde_json::to_string(&db_user.username).unwrap(),
+        db_user.role.name(),
+        db_user.score,
+        db_user.hint_costs(),
+        db_user.totp.as_ref().is_some_and(|t| t.enabled),
+        db_user.oidc_subject.is_some()
+    )
 }
 
 #[derive(Debug, Deserialize)]
-struct FlagSubmition {
+struct ChangePassword {
     username: String,
     auth_key: String,
-    challenge_id: u16,
-    flag: String,
+    current_password: String,
+    new_password: String,
+    confirm_password: String,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, Eq)]
-struct User {
-    id: u32,
+async fn change_password(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(req): Json<ChangePassword>,
+) -> String {
+    let pass_hash = {
+        let db = state.database.lock().unwrap();
+        if let Err(e) = db.authenticate_session(&req.username, &req.auth_key) {
+            return e.json().to_string();
+        }
+        db.get(&req.username).unwrap().password.to_owned()
+    };
+
+    // current password has to be confirmed
+    if !verify_password_blocking(&req.current_password, pass_hash.to_owned()).await {
+        log_stdout(format!(
+            "Password change {RED}failed{RESET} with: {BLUE}Wrong password{RESET} [username: {}]",
+            req.username
+        ));
+        return "{\"status\":\"FAIL\",\"cause\":\"Wrong password\"}".to_string();
+    }
+
+    // passwords do not match
+    if req.new_password != req.confirm_password {
+        return "{\"status\":\"FAIL\",\"cause\":\"Passwords do not match\"}".to_string();
+    }
+
+    // password wrong length
+    if req.new_password.len() < 4 || req.new_password.len() > 64 {
+        return "{\"status\":\"FAIL\",\"cause\":\"Password length should be in the range: 4-64\"}"
+            .to_string();
+    }
+
+    let new_hash = hash_password_blocking(&req.new_password).await;
+    let mut db = state.database.lock().unwrap();
+    // the session may have been revoked, or the password changed, while the lock was released
+    if let Err(e) = db.authenticate_session(&req.username, &req.auth_key) {
+        return e.json().to_string();
+    }
+    let mut u = db.get(&req.username).unwrap().clone();
+    if u.password != pass_hash {
+        return "{\"status\":\"FAIL\",\"cause\":\"Password was changed in the meantime\"}"
+            .to_string();
+    }
+    u.password = new_hash;
+    db.set(req.username.to_owned(), u).unwrap();
+
+    // every existing session and API token stops working, the caller gets a fresh session
+    db.revoke_user_sessions(&req.username).unwrap();
+    let auth_key = db.create_session(&req.username, addr.ip()).unwrap();
+
+    log_stdout(format!(
+        "Password change {GOLD}success{RESET} [username: {}]",
+        req.username
+    ));
+
+    format!("{{\"status\":\"OK\",\"auth_key\":\"{auth_key}\"}}")
+}
+
+#[derive(Debug, Deserialize)]
+struct ChangeEmail {
     username: String,
+    auth_key: String,
+    current_password: String,
     email: String,
-    password: String,
-    score: u32,
-    solves: Vec<u16>,
 }
 
-impl PartialEq for User {
-    fn eq(&self, other: &Self) -> bool {
-        self.score == other.score && self.username == other.username
-    }
+async fn change_email(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<ChangeEmail>,
+) -> &'static str {
+    let pass_hash = {
+        let db = state.database.lock().unwrap();
+        if let Err(e) = db.authenticate_session(&req.username, &req.auth_key) {
+            return e.json();
+        }
+        db.get(&req.username).unwrap().password.to_owned()
+    };
+
+    // current password has to be confirmed
+    if !verify_password_blocking(&req.current_password, pass_hash).await {
+        log_stdout(format!(
+            "Email change {RED}failed{RESET} with: {BLUE}Wrong password{RESET} [username: {}]",
+            req.username
+        ));
+        return "{\"status\":\"FAIL\",\"cause\":\"Wrong password\"}";
+    }
+
+    let email = req.email.trim();
+    if email.is_empty() {
+        return "{\"status\":\"FAIL\",\"cause\":\"Fields must not be empty\"}";
+    }
+    if email_error(email).is_some() {
+        return "{\"status\":\"FAIL\",\"cause\":\"Invalid e-mail address\"}";
+    }
+    if email_domain_error(email).is_some() {
+        log_stdout(format!(
+            "Email change {RED}failed{RESET} with: {BLUE}E-mail domain not allowed{RESET} [username: {}] [email: {}]",
+            req.username,
+            Sensitive(email, LOG_EMAILS)
+        ));
+        return "{\"status\":\"FAIL\",\"cause\":\"E-mail domain not allowed\"}";
+    }
+    let mut db = state.database.lock().unwrap();
+    if let Err(e) = db.authenticate_session(&req.username, &req.auth_key) {
+        return e.json();
+    }
+    if db.email_taken(email, &req.username) {
+        log_stdout(format!(
+            "Email change {RED}failed{RESET} with: {BLUE}E-mail already registered{RESET} [username: {}]",
+            req.username
+        ));
+        return "{\"status\":\"FAIL\",\"cause\":\"E-mail already registered\"}";
+    }
+
+    log_stdout(format!(
+        "Email change {GOLD}success{RESET} [username: {}] [email: {}]",
+        req.username,
+        Sensitive(email, LOG_EMAILS)
+    ));
+
+    let mut u = db.get(&req.username).unwrap().clone();
+    u.email = email.to_string();
+    db.set(req.username, u).unwrap();
+
+    "{\"status\":\"OK\"}"
+}
+
+#[derive(Debug, Deserialize)]
+struct ForgotPassword {
+    username: String,
+}
+
+async fn forgot_password(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(mut req): Json<ForgotPassword>,
+) -> Result<&'static str, (StatusCode, &'static str)> {
+    req.username = normalize_username(&req.username);
+
+    // no flooding someone's mailbox, or the mail relay
+    if !state.reset_limiter.check(&[
+        format!("ip:{}", addr.ip()),
+        format!("user:{}", req.username),
+    ]) {
+        log_stdout(format!(
+            "Password reset request {RED}throttled{RESET} [ip: {}] [username: {}]",
+            addr.ip(),
+            req.username
+        ));
+        return Err((
+            StatusCode::TOO_MANY_REQUESTS,
+            "{\"status\":\"FAIL\",\"cause\":\"Too many password reset requests, try again later\"}",
+        ));
+    }
+
+    // the response is always the same, so it can't be used to probe for accounts
+    let ok = Ok("{\"status\":\"OK\",\"message\":\"If the account exists, a password reset e-mail has been sent\"}");
+
+    let mut db = state.database.lock().unwrap();
+    let email = match db.get(&req.username) {
+        Some(u) => u.email.clone(),
+        None => {
+            log_stdout(format!(
+                "Password reset request {RED}failed{RESET} with: {BLUE}User not found{RESET} [username: {}]",
+                req.username
+            ));
+            return ok;
+        }
+    };
+    let token = db.create_password_reset(&req.username).unwrap();
+    drop(db);
+
+    log_stdout(format!(
+        "Password reset {GOLD}requested{RESET} [username: {}] [email: {}]",
+        req.username,
+        Sensitive(&email, LOG_EMAILS)
+    ));
+    let text = format!(
+        "Hi {},\n\nsomeone (hopefully you) asked to reset your HostCTF password.\nUse the following link within {} minutes to choose a new one:\n\n{PASSWORD_RESET_URL}{token}\n\nIf you didn't ask for this, you can ignore this e-mail.",
+        req.username,
+        PASSWORD_RESET_LIFETIME / 60
+    );
+    // delivery can take a while (SMTP), don't hold the request for it
+    tokio::task::spawn_blocking(move || {
+        if let Err(err) = send_mail(&email, "HostCTF password reset", &text) {
+            log_stdout(format!(
+                "Password reset mail {RED}failed{RESET} with: {BLUE}{err}{RESET} [email: {}]",
+                Sensitive(&email, LOG_EMAILS)
+            ));
+        }
+    });
+
+    ok
+}
+
+#[derive(Debug, Deserialize)]
+struct ResetPassword {
+    token: String,
+    password: String,
+    confirm_password: String,
+}
+
+async fn reset_password(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<ResetPassword>,
+) -> &'static str {
+    // checked before the token is consumed so a typo doesn't burn it
+    if req.password != req.confirm_password {
+        return "{\"status\":\"FAIL\",\"cause\":\"Passwords do not match\"}";
+    }
+    if req.password.len() < 4 || req.password.len() > 64 {
+        return "{\"status\":\"FAIL\",\"cause\":\"Password length should be in the range: 4-64\"}";
+    }
+
+    // hashing before taking the lock, it's slow on purpose
+    let new_hash = hash_password_blocking(&req.password).await;
+    let mut db = state.database.lock().unwrap();
+    let username = match db.take_password_reset(&req.token).unwrap() {
+        Some(username) => username,
+        None => {
+            log_stdout(format!(
+                "Password reset {RED}failed{RESET} with: {BLUE}Invalid or expired token{RESET}"
+            ));
+            return "{\"status\":\"FAIL\",\"cause\":\"Invalid or expired reset token\"}";
+        }
+    };
+
+    let mut u = db.get(&username).unwrap().clone();
+    u.password = new_hash;
+    db.set(username.to_owned(), u).unwrap();
+    db.revoke_user_sessions(&username).unwrap();
+    // owning the mailbox is enough to get back in
+    db.clear_lockout(&username).unwrap();
+
+    log_stdout(format!(
+        "Password reset {GOLD}success{RESET} [username: {username}]"
+    ));
+
+    "{\"status\":\"OK\"}"
+}
+
+#[derive(Debug, Deserialize)]
+struct LogoutRequest {
+    username: String,
+    auth_key: String,
+}
+
+async fn logout(
+    State(state): State<Arc<AppState>>,
+    Json(user): Json<LogoutRequest>,
+) -> &'static str {
+    let mut db = state.database.lock().unwrap();
+    if !db.is_session_valid(&user.username, &user.auth_key) {
+        return "{\"status\":\"FAIL\",\"cause\":\"Authentication failed\"}";
+    }
+    db.revoke_session(&user.auth_key).unwrap();
+    log_stdout(format!("User {BLUE}{}{RESET} logged out", user.username));
+
+    "{\"status\":\"OK\"}"
+}
+
+// sends the browser to the identity provider
+async fn oidc_login(State(state): State<Arc<AppState>>) -> Response {
+    if OIDC_ISSUER.is_empty() {
+        return (StatusCode::NOT_FOUND, "OIDC login is not configured").into_response();
+    }
+    match start_oidc_flow(&state, None).await {
+        Some(url) => Redirect::to(&url).into_response(),
+        None => (StatusCode::BAD_GATEWAY, "Identity provider unreachable").into_response(),
+    }
+}
+
+// returns the authorization url, None if the provider can't be reached
+async fn start_oidc_flow(state: &AppState, link_to: Option<String>) -> Option<String> {
+    let provider = match oidc_discover().await {
+        Ok(provider) => provider,
+        Err(e) => {
+            log_stdout(format!(
+                "OIDC login {RED}failed{RESET} with: {BLUE}Discovery failed: {e}{RESET}"
+            ));
+            return None;
+        }
+    };
+
+    let oidc_state = generate_token();
+    let flow = OidcFlow {
+        nonce: generate_token(),
+        code_verifier: generate_token(),
+        expires: get_unix_time() + OIDC_FLOW_LIFETIME,
+        link_to,
+    };
+    let url = oidc_authorization_url(&provider, &oidc_state, &flow);
+    let mut flows = state.oidc_flows.lock().unwrap();
+    let now = get_unix_time();
+    flows.retain(|_, f| f.expires > now);
+    flows.insert(oidc_state, flow);
+    Some(url)
+}
+
+#[derive(Debug, Deserialize)]
+struct OidcLink {
+    username: String,
+    auth_key: String,
+}
+
+// same as a login, but the callback hands the identity back for /oidc/link/confirm
+async fn oidc_link(State(state): State<Arc<AppState>>, Json(req): Json<OidcLink>) -> String {
+    if OIDC_ISSUER.is_empty() {
+        return "{\"status\":\"FAIL\",\"cause\":\"OIDC login is not configured\"}".to_string();
+    }
+    if let Err(e) = state
+        .database
+        .lock()
+        .unwrap()
+        .authenticate_session(&req.username, &req.auth_key)
+    {
+        return e.json().to_string();
+    }
+    match start_oidc_flow(&state, Some(req.username)).await {
+        Some(url) => format!(
+            "{{\"status\":\"OK\",\"url\":{}}}",
+            serde_json::to_string(&url).unwrap()
+        ),
+        None => "{\"status\":\"FAIL\",\"cause\":\"Identity provider unreachable\"}".to_string(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OidcLinkConfirm {
+    username: String,
+    auth_key: String,
+    link_token: String,
+}
+
+// the frontend holding the session which started the link finishes it,
+// so a link flow sent to someone else can't attach their identity
+async fn oidc_link_confirm(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<OidcLinkConfirm>,
+) -> String {
+    let mut db = state.database.lock().unwrap();
+    if let Err(e) = db.authenticate_session(&req.username, &req.auth_key) {
+        return e.json().to_string();
+    }
+    let subject = {
+        let mut pending = state.pending_links.lock().unwrap();
+        let now = get_unix_time();
+        pending.retain(|_, (_, _, expires)| *expires > now);
+        match pending.remove(&hash_token(&req.link_token)) {
+            Some((username, subject, _)) if username == req.username => subject,
+            _ => {
+                return "{\"status\":\"FAIL\",\"cause\":\"Link expired, please try again\"}"
+                    .to_string();
+            }
+        }
+    };
+    match db.link_oidc(&req.username, &subject).unwrap() {
+        Ok(()) => {
+            log_stdout(format!(
+                "OIDC identity {GOLD}linked{RESET} [username: {}] [sub: {subject}]",
+                req.username
+            ));
+            "{\"status\":\"OK\"}".to_string()
+        }
+        Err(cause) => {
+            log_stdout(format!(
+                "OIDC link {RED}failed{RESET} with: {BLUE}{cause}{RESET} [username: {}] [sub: {subject}]",
+                req.username
+            ));
+            format!("{{\"status\":\"FAIL\",\"cause\":\"{cause}\"}}")
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OidcCallback {
+    state: Option<String>,
+    code: Option<String>,
+    error: Option<String>,
+}
+
+// the identity provider sends the browser back here, the result goes to the frontend in the fragment
+async fn oidc_callback(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(callback): Query<OidcCallback>,
+) -> Redirect {
+    let fail = |cause: &str| {
+        Redirect::to(&format!(
+            "{OIDC_FRONTEND_URL}#status=FAIL&cause={}",
+            percent_encode(cause)
+        ))
+    };
+
+    let flow = callback
+        .state
+        .and_then(|s| state.oidc_flows.lock().unwrap().remove(&s))
+        .filter(|f| f.expires > get_unix_time());
+    let flow = match flow {
+        Some(flow) => flow,
+        None => return fail("Login expired, please try again"),
+    };
+    if let Some(error) = callback.error {
+        log_stdout(format!(
+            "OIDC login {RED}failed{RESET} with: {BLUE}Provider returned {error}{RESET}"
+        ));
+        return fail("Login was cancelled at the identity provider");
+    }
+    let claims = match oidc_exchange_code(&callback.code.unwrap_or_default(), &flow).await {
+        Ok(claims) => claims,
+        Err(e) => {
+            log_stdout(format!(
+                "OIDC login {RED}failed{RESET} with: {BLUE}{e}{RESET}"
+            ));
+            return fail("Login at the identity provider failed");
+        }
+    };
+
+    if let Some(link_to) = flow.link_to {
+        let token = generate_token();
+        state.pending_links.lock().unwrap().insert(
+            hash_token(&token),
+            (link_to, claims.sub, get_unix_time() + OIDC_FLOW_LIFETIME),
+        );
+        return Redirect::to(&format!(
+            "{OIDC_FRONTEND_URL}#status=link_pending&link_token={token}"
+        ));
+    }
+
+    let mut db = state.database.lock().unwrap();
+    let username = match db.oidc_user(&claims).unwrap() {
+        Some(username) => username,
+        None => {
+            let cause = db.oidc_signup_error(&claims).unwrap();
+            log_stdout(format!(
+                "OIDC register attempt {RED}failed{RESET} with: {BLUE}{cause}{RESET} [sub: {}]",
+                claims.sub
+            ));
+            return fail(cause);
+        }
+    };
+    let db_user = db.get(&username).unwrap();
+
+    // accounts with two-factor authentication still need their code
+    if db_user.totp.as_ref().is_some_and(|t| t.enabled) {
+        log_stdout(format!(
+            "OIDC login {GOLD}awaiting two-factor code{RESET} [username: {username}]"
+        ));
+        let token = generate_token();
+        let now = get_unix_time();
+        let mut pending = state.pending_logins.lock().unwrap();
+        pending.retain(|_, (_, expires)| *expires > now);
+        pending.insert(
+            hash_token(&token),
+            (username.to_owned(), now + LOGIN_2FA_LIFETIME),
+        );
+        return Redirect::to(&format!(
+            "{OIDC_FRONTEND_URL}#status=2fa_required&username={}&login_token={token}",
+            percent_encode(&username)
+        ));
+    }
+
+    log_stdout(format!(
+        "OIDC login {GOLD}success{RESET} [username: {username}]"
+    ));
+    let solved_chals = serde_json::to_string(&db_user.solves).unwrap();
+    let totp_setup_required = db_user.needs_totp_setup();
+    let auth_key = db.create_session(&username, addr.ip()).unwrap();
+    Redirect::to(&format!(
+        "{OIDC_FRONTEND_URL}#status=OK&username={}&auth_key={auth_key}&solved_chals={}&totp_setup_required={totp_setup_required}",
+        percent_encode(&username),
+        percent_encode(&solved_chals)
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+struct Login2fa {
+    login_token: String,
+    totp_code: String,
+}
+
+// second step of logins which didn't send a password, like OIDC
+async fn login_2fa(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(req): Json<Login2fa>,
+) -> Result<String, (StatusCode, &'static str)> {
+    let token_hash = hash_token(&req.login_token);
+    let username = match state.pending_logins.lock().unwrap().get(&token_hash) {
+        Some((username, expires)) if *expires > get_unix_time() => username.to_owned(),
+        _ => {
+            return Ok(
+                "{\"status\":\"FAIL\",\"cause\":\"Login expired, please log in again\"}"
+                    .to_string(),
+            );
+        }
+    };
+
+    // no code bruteforcing either
+    if !state
+        .login_limiter
+        .check(&[format!("ip:{}", addr.ip()), format!("user:{username}")])
+    {
+        log_stdout(format!(
+            "Login attempt {RED}throttled{RESET} [ip: {}] [username: {username}]",
+            addr.ip()
+        ));
+        return Err((
+            StatusCode::TOO_MANY_REQUESTS,
+            "{\"status\":\"FAIL\",\"cause\":\"Too many login attempts, try again later\"}",
+        ));
+    }
+
+    let mut db = state.database.lock().unwrap();
+    // locked accounts don't get to try codes either, not even on logins started before the lock
+    let now = get_unix_time();
+    let locked_until = db.get(&username).unwrap().locked_until;
+    if locked_until > now {
+        log_stdout(format!(
+            "Login attempt {RED}failed{RESET} with: {BLUE}Account locked{RESET} [username: {username}]"
+        ));
+        state.drop_pending_logins(&username);
+        return Ok(format!(
+            "{{\"status\":\"FAIL\",\"cause\":\"Account locked, try again in {}\"}}",
+            format_duration(locked_until - now)
+        ));
+    }
+    if !db.verify_second_factor(&username, &req.totp_code).unwrap() {
+        log_stdout(format!(
+            "Login attempt {RED}failed{RESET} with: {BLUE}Wrong two-factor code{RESET} [username: {username}]"
+        ));
+        if let Some(duration) = db.record_failed_login(&username).unwrap() {
+            log_stdout(format!(
+                "Account {RED}locked{RESET} for {} [username: {username}]",
+                format_duration(duration)
+            ));
+            state.drop_pending_logins(&username);
+        }
+        return Ok("{\"status\":\"FAIL\",\"cause\":\"Wrong two-factor code\"}".to_string());
+    }
+
+    state.pending_logins.lock().unwrap().remove(&token_hash);
+    log_stdout(format!(
+        "Login attempt {GOLD}success{RESET} [username: {username}]"
+    ));
+    db.clear_lockout(&username).unwrap();
+    let db_user = db.get(&username).unwrap();
+    let solved_chals = serde_json::to_string(&db_user.solves).unwrap();
+    let totp_setup_required = db_user.needs_totp_setup();
+    let auth_key = db.create_session(&username, addr.ip()).unwrap();
+
+    Ok(format!(
+        "{{\"status\":\"OK\",\"auth_key\":\"{auth_key}\",\"solved_chals\":{solved_chals},\"totp_setup_required\":{totp_setup_required}}}"
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+struct TotpSetup {
+    username: String,
+    auth_key: String,
+}
+
+// generates a fresh secret, it only becomes active after /2fa/enable
+async fn totp_setup(State(state): State<Arc<AppState>>, Json(req): Json<TotpSetup>) -> String {
+    let mut db = state.database.lock().unwrap();
+    if !db.is_session_valid(&req.username, &req.auth_key) {
+        return "{\"status\":\"FAIL\",\"cause\":\"Authentication failed\"}".to_string();
+    }
+    if db
+        .get(&req.username)
+        .unwrap()
+        .totp
+        .as_ref()
+        .is_some_and(|t| t.enabled)
+    {
+        return "{\"status\":\"FAIL\",\"cause\":\"Two-factor authentication is already enabled\"}"
+            .to_string();
+    }
+
+    let secret = db.begin_totp_setup(&req.username).unwrap();
+    let uri = totp_uri(&req.username, &secret);
+    format!(
+        "{{\"status\":\"OK\",\"secret\":\"{secret}\",\"uri\":{},\"qr_svg\":{}}}",
+        serde_json::to_string(&uri).unwrap(),
+        serde_json::to_string(&qr_svg(&uri)).unwrap()
+    )
+}
+
+#[derive(Debug, Deserialize)]
+struct TotpEnable {
+    username: String,
+    auth_key: String,
+    code: String,
+}
+
+async fn totp_enable(State(state): State<Arc<AppState>>, Json(req): Json<TotpEnable>) -> String {
+    let mut db = state.database.lock().unwrap();
+    if !db.is_session_valid(&req.username, &req.auth_key) {
+        return "{\"status\":\"FAIL\",\"cause\":\"Authentication failed\"}".to_string();
+    }
+
+    let recovery_codes = match db.enable_totp(&req.username, &req.code).unwrap() {
+        Some(codes) => codes,
+        None => {
+            return "{\"status\":\"FAIL\",\"cause\":\"Wrong two-factor code\"}".to_string();
+        }
+    };
+    log_stdout(format!(
+        "Two-factor authentication {GOLD}enabled{RESET} [username: {}]",
+        req.username
+    ));
+
+    // shown exactly once, only their hashes are stored
+    format!(
+        "{{\"status\":\"OK\",\"recovery_codes\":{}}}",
+        serde_json::to_string(&recovery_codes).unwrap()
+    )
+}
+
+#[derive(Debug, Deserialize)]
+struct TotpDisable {
+    username: String,
+    auth_key: String,
+    current_password: String,
+    code: String,
+}
+
+async fn totp_disable(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<TotpDisable>,
+) -> &'static str {
+    let pass_hash = {
+        let db = state.database.lock().unwrap();
+        if !db.is_session_valid(&req.username, &req.auth_key) {
+            return "{\"status\":\"FAIL\",\"cause\":\"Authentication failed\"}";
+        }
+        let db_user = db.get(&req.username).unwrap();
+        if db_user.totp_required {
+            return "{\"status\":\"FAIL\",\"cause\":\"Two-factor authentication is required for this account\"}";
+        }
+        db_user.password.to_owned()
+    };
+    if !verify_password_blocking(&req.current_password, pass_hash).await {
+        return "{\"status\":\"FAIL\",\"cause\":\"Wrong password\"}";
+    }
+
+    let mut db = state.database.lock().unwrap();
+    if !db.is_session_valid(&req.username, &req.auth_key) {
+        return "{\"status\":\"FAIL\",\"cause\":\"Authentication failed\"}";
+    }
+    if db.get(&req.username).unwrap().totp_required {
+        return "{\"status\":\"FAIL\",\"cause\":\"Two-factor authentication is required for this account\"}";
+    }
+    if !db.verify_second_factor(&req.username, &req.code).unwrap() {
+        return "{\"status\":\"FAIL\",\"cause\":\"Wrong two-factor code\"}";
+    }
+
+    db.disable_totp(&req.username).unwrap();
+    log_stdout(format!(
+        "Two-factor authentication {RED}disabled{RESET} [username: {}]",
+        req.username
+    ));
+
+    "{\"status\":\"OK\"}"
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiTokensRequest {
+    username: String,
+    auth_key: String,
+}
+
+// tokens can only be managed with a session, never with another token
+async fn api_tokens(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<ApiTokensRequest>,
+) -> String {
+    let db = state.database.lock().unwrap();
+    if let Err(e) = db.authenticate_session(&req.username, &req.auth_key) {
+        return e.json().to_string();
+    }
+    format!(
+        "{{\"status\":\"OK\",\"tokens\":{}}}",
+        serde_json::to_string(&db.get_api_tokens(&req.username)).unwrap()
+    )
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiTokenCreate {
+    username: String,
+    auth_key: String,
+    name: String,
+    scope: ApiScope,
+}
+
+async fn api_token_create(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<ApiTokenCreate>,
+) -> String {
+    let mut db = state.database.lock().unwrap();
+    if let Err(e) = db.authenticate_session(&req.username, &req.auth_key) {
+        return e.json().to_string();
+    }
+    let name = req.name.trim();
+    if name.is_empty() || name.len() > 64 {
+        return "{\"status\":\"FAIL\",\"cause\":\"Token name length should be in the range: 1-64\"}"
+            .to_string();
+    }
+
+    let token = db.create_api_token(&req.username, name, req.scope).unwrap();
+    log_stdout(format!(
+        "API token {GOLD}created{RESET} [username: {}] [name: {name}] [scope: {:?}]",
+        req.username, req.scope
+    ));
+
+    // shown exactly once, only its hash is stored
+    format!("{{\"status\":\"OK\",\"token\":\"{token}\"}}")
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiTokenRevoke {
+    username: String,
+    auth_key: String,
+    id: String,
+}
+
+async fn api_token_revoke(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<ApiTokenRevoke>,
+) -> &'static str {
+    let mut db = state.database.lock().unwrap();
+    if let Err(e) = db.authenticate_session(&req.username, &req.auth_key) {
+        return e.json();
+    }
+    if !db.revoke_api_token(&req.username, &req.id).unwrap() {
+        return "{\"status\":\"FAIL\",\"cause\":\"Token doesnt exist\"}";
+    }
+    log_stdout(format!(
+        "API token {RED}revoked{RESET} [username: {}] [id: {}]",
+        req.username, req.id
+    ));
+
+    "{\"status\":\"OK\"}"
+}
+
+// user which passed `require_role`, for the guarded handlers
+#[derive(Clone)]
+struct AuthorizedUser(String);
+
+// guards a group of routes, only lets through sessions (sent as `Authorization: Bearer <auth_key>`)
+// of users with at least the given role, API tokens resolve the same way but never carry a role
+async fn require_role(
+    State((state, role)): State<(Arc<AppState>, Role)>,
+    mut req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let allowed = {
+        let mut db = state.database.lock().unwrap();
+        match get_bearer_token(req.headers()).and_then(|t| db.resolve_bearer(t, ApiScope::Read)) {
+            Some(BearerAuth::Session(username)) => {
+                if db
+                    .get(&username)
+                    .is_some_and(|u| u.role >= role && !u.needs_totp_setup())
+                {
+                    Ok(username)
+                } else {
+                    Err("Not allowed")
+                }
+            }
+            Some(BearerAuth::ApiToken(_)) => Err("API tokens can't be used for staff routes"),
+            None => Err("Not allowed"),
+        }
+    };
+    let username = match allowed {
+        Ok(username) => username,
+        Err(cause) => {
+            log_stdout(format!(
+                "Access {RED}denied{RESET} with: {BLUE}{cause}{RESET} [path: {}] [required role: {}]",
+                req.uri().path(),
+                role.name()
+            ));
+            return (
+                StatusCode::FORBIDDEN,
+                format!("{{\"status\":\"FAIL\",\"cause\":\"{cause}\"}}"),
+            )
+                .into_response();
+        }
+    };
+    req.extensions_mut().insert(AuthorizedUser(username));
+    next.run(req).await
+}
+
+#[derive(Debug, Deserialize)]
+struct AdminUserRequest {
+    username: String,
+}
+
+async fn admin_logout_everywhere(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(AuthorizedUser(admin)): Extension<AuthorizedUser>,
+    Json(req): Json<AdminUserRequest>,
+) -> String {
+    let mut db = state.database.lock().unwrap();
+    if db.get(&req.username).is_none() {
+        return "{\"status\":\"FAIL\",\"cause\":\"User doesnt exist\"}".to_string();
+    }
+    let (revoked, revoked_tokens) = db.revoke_user_sessions(&req.username).unwrap();
+    AuditEntry::new(
+        &admin,
+        Some(addr.ip()),
+        "logout_everywhere",
+        "user",
+        &req.username,
+    )
+    .before(format!("{revoked} sessions, {revoked_tokens} API tokens"))
+    .after("0 sessions, 0 API tokens")
+    .append()
+    .unwrap();
+    log_stdout(format!(
+        "Admin {BLUE}{admin}{RESET} {GOLD}logged out{RESET} user {BLUE}{}{RESET} everywhere ({revoked} sessions, {revoked_tokens} API tokens)",
+        req.username
+    ));
+
+    format!("{{\"status\":\"OK\",\"revoked\":{revoked},\"revoked_api_tokens\":{revoked_tokens}}}")
+}
+
+async fn admin_unlock(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(AuthorizedUser(admin)): Extension<AuthorizedUser>,
+    Json(req): Json<AdminUserRequest>,
+) -> &'static str {
+    let mut db = state.database.lock().unwrap();
+    let before = match db.get(&req.username) {
+        Some(u) => format!(
+            "failed_logins={} locked_until={} lockouts={}",
+            u.failed_logins, u.locked_until, u.lockouts
+        ),
+        None => return "{\"status\":\"FAIL\",\"cause\":\"User doesnt exist\"}",
+    };
+    db.clear_lockout(&req.username).unwrap();
+    AuditEntry::new(&admin, Some(addr.ip()), "unlock", "user", &req.username)
+        .before(before)
+        .after("failed_logins=0 locked_until=0 lockouts=0")
+        .append()
+        .unwrap();
+    log_stdout(format!(
+        "Admin {BLUE}{admin}{RESET} {GOLD}unlocked{RESET} user {BLUE}{}{RESET}",
+        req.username
+    ));
+
+    "{\"status\":\"OK\"}"
+}
+
+#[derive(Debug, Deserialize)]
+struct AdminRequire2fa {
+    username: String,
+    required: bool,
+}
+
+async fn admin_require_2fa(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(AuthorizedUser(admin)): Extension<AuthorizedUser>,
+    Json(req): Json<AdminRequire2fa>,
+) -> &'static str {
+    let mut db = state.database.lock().unwrap();
+    let mut u = match db.get(&req.username) {
+        Some(u) => u.clone(),
+        None => return "{\"status\":\"FAIL\",\"cause\":\"User doesnt exist\"}",
+    };
+    let before = u.totp_required;
+    u.totp_required = req.required;
+    db.set(req.username.to_owned(), u).unwrap();
+    AuditEntry::new(
+        &admin,
+        Some(addr.ip()),
+        "require_2fa",
+        "user",
+        &req.username,
+    )
+    .before(before)
+    .after(req.required)
+    .append()
+    .unwrap();
+    log_stdout(format!(
+        "Admin {BLUE}{admin}{RESET} set two-factor requirement of user {BLUE}{}{RESET} to {GOLD}{}{RESET}",
+        req.username, req.required
+    ));
+
+    "{\"status\":\"OK\"}"
+}
+
+#[derive(Debug, Deserialize)]
+struct AdminSetRole {
+    username: String,
+    role: Role,
+}
+
+async fn admin_set_role(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(AuthorizedUser(admin)): Extension<AuthorizedUser>,
+    Json(req): Json<AdminSetRole>,
+) -> &'static str {
+    // keeps the last admin from locking everyone out
+    if req.username == admin {
+        return "{\"status\":\"FAIL\",\"cause\":\"You cant change your own role\"}";
+    }
+    let mut db = state.database.lock().unwrap();
+    let before = match db.get(&req.username) {
+        Some(u) => u.role,
+        None => return "{\"status\":\"FAIL\",\"cause\":\"User doesnt exist\"}",
+    };
+    db.set_role(&req.username, req.role).unwrap();
+    AuditEntry::new(&admin, Some(addr.ip()), "set_role", "user", &req.username)
+        .before(before.name())
+        .after(req.role.name())
+        .append()
+        .unwrap();
+    log_stdout(format!(
+        "Admin {BLUE}{admin}{RESET} set role of user {BLUE}{}{RESET} to {GOLD}{}{RESET}",
+        req.username,
+        req.role.name()
+    ));
+
+    "{\"status\":\"OK\"}"
+}
+
+async fn admin_invites(State(state): State<Arc<AppState>>) -> String {
+    let db = state.database.lock().unwrap();
+    format!(
+        "{{\"status\":\"OK\",\"registration_mode\":\"{}\",\"invites\":{}}}",
+        REGISTRATION_MODE.name(),
+        serde_json::to_string(&db.get_invites()).unwrap()
+    )
+}
+
+#[derive(Debug, Deserialize)]
+struct AdminInviteCreate {
+    max_uses: u32,
+}
+
+async fn admin_invite_create(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(AuthorizedUser(admin)): Extension<AuthorizedUser>,
+    Json(req): Json<AdminInviteCreate>,
+) -> String {
+    if req.max_uses == 0 {
+        return "{\"status\":\"FAIL\",\"cause\":\"An invite code needs at least one use\"}"
+            .to_string();
+    }
+    let code = state
+        .database
+        .lock()
+        .unwrap()
+        .create_invite(req.max_uses, &admin)
+        .unwrap();
+    AuditEntry::new(&admin, Some(addr.ip()), "invite_create", "invite", &code)
+        .after(format!("{} uses", req.max_uses))
+        .append()
+        .unwrap();
+    log_stdout(format!(
+        "Admin {BLUE}{admin}{RESET} {GOLD}created{RESET} an invite code [uses: {}]",
+        req.max_uses
+    ));
+
+    format!("{{\"status\":\"OK\",\"code\":\"{code}\"}}")
+}
+
+#[derive(Debug, Deserialize)]
+struct AdminInviteRevoke {
+    code: String,
+}
+
+async fn admin_invite_revoke(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(AuthorizedUser(admin)): Extension<AuthorizedUser>,
+    Json(req): Json<AdminInviteRevoke>,
+) -> &'static str {
+    let mut db = state.database.lock().unwrap();
+    let before = match db.db.invites.get(&req.code) {
+        Some(invite) => format!("{}/{} uses", invite.uses, invite.max_uses),
+        None => return "{\"status\":\"FAIL\",\"cause\":\"Invite code doesnt exist\"}",
+    };
+    db.revoke_invite(&req.code).unwrap();
+    AuditEntry::new(
+        &admin,
+        Some(addr.ip()),
+        "invite_revoke",
+        "invite",
+        &req.code,
+    )
+    .before(before)
+    .append()
+    .unwrap();
+    log_stdout(format!(
+        "Admin {BLUE}{admin}{RESET} {RED}revoked{RESET} an invite code"
+    ));
+
+    "{\"status\":\"OK\"}"
+}
+
+// one privileged action, stored as a line of json in AUDIT_LOG_FILE
+#[derive(Debug, Deserialize, Serialize)]
+struct AuditEntry {
+    time: u64,
+    actor: String,
+    // none for actions taken on the command line
+    ip: Option<IpAddr>,
+    action: String,
+    // kind and id of the changed entity, e.g. "user" and "alice"
+    entity: String,
+    target: String,
+    before: Option<String>,
+    after: Option<String>,
+}
+
+impl AuditEntry {
+    fn new(actor: &str, ip: Option<IpAddr>, action: &str, entity: &str, target: &str) -> Self {
+        Self {
+            time: get_unix_time(),
+            actor: actor.to_string(),
+            ip,
+            action: action.to_string(),
+            entity: entity.to_string(),
+            target: target.to_string(),
+            before: None,
+            after: None,
+        }
+    }
+
+    fn before(mut self, value: impl ToString) -> Self {
+        self.before = Some(value.to_string());
+        self
+    }
+
+    fn after(mut self, value: impl ToString) -> Self {
+        self.after = Some(value.to_string());
+        self
+    }
+
+    // the file is only ever appended to, never rewritten
+    fn append(&self) -> Result<(), Box<dyn Error>> {
+        let mut line = serde_json::to_string(self)?;
+        line.push('\n');
+        let mut fh = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(AUDIT_LOG_FILE)?;
+        fh.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    fn matches(&self, filter: &AuditFilter) -> bool {
+        (filter.actor.is_empty() || self.actor == filter.actor)
+            && (filter.action.is_empty() || self.action == filter.action)
+            && (filter.target.is_empty() || self.target == filter.target)
+    }
+}
+
+// empty fields match every entry
+#[derive(Debug, Deserialize, Default)]
+struct AuditFilter {
+    #[serde(default)]
+    actor: String,
+    #[serde(default)]
+    action: String,
+    #[serde(default)]
+    target: String,
+}
+
+// matching entries, oldest first
+fn read_audit_log(filter: &AuditFilter) -> Result<Vec<AuditEntry>, Box<dyn Error>> {
+    if !Path::new(AUDIT_LOG_FILE).exists() {
+        return Ok(Vec::new());
+    }
+    let mut entries = Vec::new();
+    for (i, line) in read_to_string(AUDIT_LOG_FILE)?.lines().enumerate() {
+        // a torn write or a hand edit shouldn't hide the rest of the log
+        let entry: AuditEntry = match serde_json::from_str(line) {
+            Ok(entry) => entry,
+            Err(e) => {
+                log_stdout(format!(
+                    "Audit log line {} {RED}skipped{RESET} with: {BLUE}{e}{RESET}",
+                    i + 1
+                ));
+                continue;
+            }
+        };
+        if entry.matches(filter) {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+// the matching entries as json lines, the same format as the file itself
+fn export_audit_log(filter: &AuditFilter) -> Result<String, Box<dyn Error>> {
+    let mut out = String::new();
+    for entry in read_audit_log(filter)? {
+        out.push_str(&serde_json::to_string(&entry)?);
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+const AUDIT_LOG_READ_FAIL: (StatusCode, &str) = (
+    StatusCode::INTERNAL_SERVER_ERROR,
+    "{\"status\":\"FAIL\",\"cause\":\"Can't read the audit log\"}",
+);
+
+async fn admin_audit(
+    Json(filter): Json<AuditFilter>,
+) -> Result<String, (StatusCode, &'static str)> {
+    let entries = read_audit_log(&filter).map_err(|e| {
+        log_stdout(format!(
+            "Audit log read {RED}failed{RESET} with: {BLUE}{e}{RESET}"
+        ));
+        AUDIT_LOG_READ_FAIL
+    })?;
+    Ok(format!(
+        "{{\"status\":\"OK\",\"entries\":{}}}",
+        serde_json::to_string(&entries).unwrap()
+    ))
+}
+
+async fn admin_audit_export(
+    Json(filter): Json<AuditFilter>,
+) -> Result<impl IntoResponse, (StatusCode, &'static str)> {
+    let out = export_audit_log(&filter).map_err(|e| {
+        log_stdout(format!(
+            "Audit log read {RED}failed{RESET} with: {BLUE}{e}{RESET}"
+        ));
+        AUDIT_LOG_READ_FAIL
+    })?;
+    Ok(([(CONTENT_TYPE, "application/x-ndjson")], out))
+}
+
+async fn author_challenges(State(state): State<Arc<AppState>>) -> String {
+    let db = state.database.lock().unwrap();
+    let solve_counts = db.db._solve_counts();
+    let first_bloods = db.db._first_bloods();
+    let hint_unlocks = db.db._hint_unlock_counts();
+    let stats: Vec<ChallengeStats> = CHALLENGES_JSON
+        .get()
+        .unwrap()
+        .iter()
+        .flat_map(|chall_cat| {
+            chall_cat.challenges.iter().map(|chall| ChallengeStats {
+                id: chall.id,
+                name: &chall.name,
+                category: &chall_cat.name,
+                points: chall.current_value(&solve_counts),
+                solves: solve_counts.get(&chall.id).copied().unwrap_or(0),
+                first_blood: first_bloods.get(&chall.id),
+                hint_unlocks: (0..chall.hints.len())
+                    .map(|i| hint_unlocks.get(&(chall.id, i)).copied().unwrap_or(0))
+                    .collect(),
+            })
+        })
+        .collect();
+    format!(
+        "{{\"status\":\"OK\",\"challenges\":{}}}",
+        serde_json::to_string(&stats).unwrap()
+    )
+}
+
+async fn admin_sharing_incidents(State(state): State<Arc<AppState>>) -> String {
+    let db = state.database.lock().unwrap();
+    format!(
+        "{{\"status\":\"OK\",\"incidents\":{}}}",
+        serde_json::to_string(&db.get_sharing_incidents()).unwrap()
+    )
+}
+
+#[derive(Debug, Deserialize)]
+struct FlagSubmition {
+    // not needed with an API token
+    #[serde(default)]
+    username: String,
+    #[serde(default)]
+    auth_key: String,
+    challenge_id: u16,
+    flag: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct HintUnlockRequest {
+    // not needed with an API token
+    #[serde(default)]
+    username: String,
+    #[serde(default)]
+    auth_key: String,
+    challenge_id: u16,
+    // index in the challenge's hints
+    hint: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct UnlockedHint<'a> {
+    challenge_id: u16,
+    hint: usize,
+    text: &'a str,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Eq)]
+struct User {
+    id: u32,
+    username: String,
+    email: String,
+    password: String,
+    // negative when a decayed challenge leaves less than was spent on hints
+    score: i64,
+    solves: Vec<u16>,
+    // wrong passwords since the last successful login or lockout
+    #[serde(default)]
+    failed_logins: u32,
+    #[serde(default)]
+    locked_until: u64,
+    // lockouts since the last successful login, picks the next duration
+    #[serde(default)]
+    lockouts: u32,
+    #[serde(default)]
+    totp: Option<Totp>,
+    // set by an admin, the account can't submit flags until it enrolls
+    #[serde(default)]
+    totp_required: bool,
+    // `sub` claim of the linked identity provider account
+    #[serde(default)]
+    oidc_subject: Option<String>,
+    #[serde(default)]
+    role: Role,
+    // kept apart from the solves, so recalculating the scores doesn't lose them
+    #[serde(default)]
+    bonuses: Vec<SolveBonus>,
+    // bought hints, the cost is kept like the bonuses
+    #[serde(default)]
+    hint_unlocks: Vec<HintUnlock>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+struct HintUnlock {
+    challenge_id: u16,
+    // index in the challenge's hints
+    hint: usize,
+    cost: u32,
+}
+
+// extra points for one of the first solvers of a challenge
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+struct SolveBonus {
+    challenge_id: u16,
+    // 1 for the first blood
+    rank: u32,
+    points: u32,
+}
+
+impl SolveBonus {
+    fn title(&self) -> String {
+        match self.rank {
+            1 => "First blood".to_string(),
+            rank => format!("Early solve #{rank}"),
+        }
+    }
+}
+
+impl User {
+    fn hint_costs(&self) -> u32 {
+        self.hint_unlocks.iter().map(|u| u.cost).sum()
+    }
+
+    // free hints count as unlocked
+    fn has_hint(&self, challenge_id: u16, hint: usize) -> bool {
+        find_challenge(challenge_id)
+            .and_then(|chal| chal.hints.get(hint))
+            .is_some_and(|h| h.cost == 0)
+            || self
+                .hint_unlocks
+                .iter()
+                .any(|u| u.challenge_id == challenge_id && u.hint == hint)
+    }
+
+    fn first_bloods(&self) -> u32 {
+        self.bonuses.iter().filter(|b| b.rank == 1).count() as u32
+    }
+
+    fn needs_totp_setup(&self) -> bool {
+        self.totp_required && !self.totp.as_ref().is_some_and(|t| t.enabled)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+struct Totp {
+    // base32, the way authenticator apps want it
+    secret: String,
+    // false until the user proves their app generates the right codes
+    enabled: bool,
+    // last accepted time step, every code works only once
+    last_step: u64,
+    // hashes of the unused recovery codes
+    recovery_codes: Vec<String>,
+}
+
+// ordered by privilege, every role can do everything the ones below it can
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+enum Role {
+    #[default]
+    Player,
+    // writes challenges
+    Author,
+    Admin,
+}
+
+impl Role {
+    fn name(self) -> &'static str {
+        match self {
+            Role::Player => "player",
+            Role::Author => "author",
+            Role::Admin => "admin",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "player" => Some(Role::Player),
+            "author" => Some(Role::Author),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+}
+
+impl PartialEq for User {
+    fn eq(&self, other: &Self) -> bool {
+        self.score == other.score && self.username == other.username
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+struct Session {
+    username: String,
+    created: u64,
+    expires: u64,
+    ip: IpAddr,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum ApiScope {
+    // profile and other read-only endpoints
+    Read,
+    // everything read can do, plus flag submission
+    Submit,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+struct ApiToken {
+    // public handle for listing and revoking, the token itself is only stored hashed
+    id: String,
+    username: String,
+    name: String,
+    scope: ApiScope,
+    created: u64,
+    last_used: Option<u64>,
+}
+
+enum BearerAuth {
+    Session(String),
+    ApiToken(String),
+}
+
+impl BearerAuth {
+    fn into_username(self) -> String {
+        match self {
+            BearerAuth::Session(username) | BearerAuth::ApiToken(username) => username,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AuthError {
+    Failed,
+    // the admin requires two-factor authentication and it isn't enabled yet
+    TotpSetupRequired,
+}
+
+impl AuthError {
+    fn cause(self) -> &'static str {
+        match self {
+            AuthError::Failed => "Authentication failed",
+            AuthError::TotpSetupRequired => "Two-factor authentication has to be set up first",
+        }
+    }
+
+    fn json(self) -> &'static str {
+        match self {
+            AuthError::Failed => "{\"status\":\"FAIL\",\"cause\":\"Authentication failed\"}",
+            AuthError::TotpSetupRequired => {
+                "{\"status\":\"FAIL\",\"cause\":\"Two-factor authentication has to be set up first\"}"
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+struct PasswordReset {
+    username: String,
+    expires: u64,
+}
+
+// someone submitted the dynamic flag of another player
+#[derive(Debug, Deserialize, Serialize, Clone)]
+struct SharingIncident {
+    time: u64,
+    challenge_id: u16,
+    username: String,
+    owner: String,
+    ip: IpAddr,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+struct Invite {
+    code: String,
+    // 1 for single-use codes
+    max_uses: u32,
+    uses: u32,
+    created: u64,
+    created_by: String,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone, Eq)]
 struct UserScoreboard {
     username: String,
-    score: u32,
+    score: i64,
+    // shown as badges
+    #[serde(default)]
+    first_bloods: u32,
 }
 
 impl Ord for UserScoreboard {
@@ -398,12 +3371,48 @@ impl PartialEq for UserScoreboard {
 #[derive(Debug)]
 struct AppState {
     database: Arc<Mutex<DB>>,
+    login_limiter: RateLimiter,
+    reset_limiter: RateLimiter,
+    flag_limiter: RateLimiter,
+    // OIDC logins waiting for the provider, by state parameter
+    oidc_flows: Mutex<HashMap<String, OidcFlow>>,
+    // logins waiting for their two-factor code, by token hash: (username, expires)
+    pending_logins: Mutex<HashMap<String, (String, u64)>>,
+    // identities waiting for /oidc/link/confirm, by token hash: (username, subject, expires)
+    pending_links: Mutex<HashMap<String, (String, String, u64)>>,
+}
+
+impl AppState {
+    // a lockout also ends the logins which are still waiting for their two-factor code
+    fn drop_pending_logins(&self, username: &str) {
+        self.pending_logins
+            .lock()
+            .unwrap()
+            .retain(|_, (pending, _)| pending != username);
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 struct DBInner {
     map: HashMap<String, User>,
     set: BTreeSet<UserScoreboard>,
+    // token hash -> session
+    #[serde(default)]
+    sessions: HashMap<String, Session>,
+    // token hash -> pending password reset
+    #[serde(default)]
+    password_resets: HashMap<String, PasswordReset>,
+    // token hash -> api token
+    #[serde(default)]
+    api_tokens: HashMap<String, ApiToken>,
+    // code -> invite
+    #[serde(default)]
+    invites: HashMap<String, Invite>,
+    // key for dynamic flags, see initialize_flag_secret()
+    #[serde(default)]
+    dynamic_flag_secret: String,
+    #[serde(default)]
+    sharing_incidents: Vec<SharingIncident>,
 }
 
 impl DBInner {
@@ -411,6 +3420,12 @@ impl DBInner {
         Self {
             map: HashMap::new(),
             set: BTreeSet::new(),
+            sessions: HashMap::new(),
+            password_resets: HashMap::new(),
+            api_tokens: HashMap::new(),
+            invites: HashMap::new(),
+            dynamic_flag_secret: String::new(),
+            sharing_incidents: Vec::new(),
         }
     }
 
@@ -425,6 +3440,7 @@ impl DBInner {
             let mut u = UserScoreboard {
                 username: existing_user.username.clone(),
                 score: existing_user.score,
+                first_bloods: existing_user.first_bloods(),
             };
 
             // Remove the existing user from the BTreeSet
@@ -437,6 +3453,7 @@ impl DBInner {
             u = UserScoreboard {
                 username: existing_user.username.clone(),
                 score: existing_user.score,
+                first_bloods: existing_user.first_bloods(),
             };
 
             // Insert the updated user back into the BTreeSet
@@ -447,6 +3464,7 @@ impl DBInner {
 
             // New user for the set
             let u = UserScoreboard {
+                first_bloods: user.first_bloods(),
                 username: user.username,
                 score: user.score,
             };
@@ -455,6 +3473,73 @@ impl DBInner {
             self.set.insert(u);
         }
     }
+
+    // how many users solved each challenge
+    fn _solve_counts(&self) -> HashMap<u16, u32> {
+        let mut counts = HashMap::new();
+        for user in self.map.values() {
+            for id in &user.solves {
+                *counts.entry(*id).or_insert(0) += 1;
+            }
+        }
+        counts
+    }
+
+    // (challenge id, hint index) -> how many users unlocked it
+    fn _hint_unlock_counts(&self) -> HashMap<(u16, usize), u32> {
+        let mut counts = HashMap::new();
+        for user in self.map.values() {
+            for unlock in &user.hint_unlocks {
+                *counts
+                    .entry((unlock.challenge_id, unlock.hint))
+                    .or_insert(0) += 1;
+            }
+        }
+        counts
+    }
+
+    // challenge id -> username
+    fn _first_bloods(&self) -> HashMap<u16, String> {
+        self.map
+            .values()
+            .flat_map(|user| {
+                user.bonuses
+                    .iter()
+                    .filter(|b| b.rank == 1)
+                    .map(|b| (b.challenge_id, user.username.clone()))
+            })
+            .collect()
+    }
+
+    // sets every score from the solves and the current challenge values, so a decayed value
+    // also lowers the earlier solvers. returns whether anything changed
+    fn _recalculate_scores(&mut self) -> bool {
+        let counts = self._solve_counts();
+        let changed: Vec<User> = self
+            .map
+            .values()
+            .filter_map(|user| {
+                let score = user
+                    .solves
+                    .iter()
+                    .filter_map(|id| find_challenge(*id))
+                    .map(|chal| chal.current_value(&counts))
+                    .sum::<u32>()
+                    + user.bonuses.iter().map(|b| b.points).sum::<u32>();
+                // spent points stay spent, even when the solves are worth less by now
+                let score = i64::from(score) - i64::from(user.hint_costs());
+                (score != user.score).then(|| User {
+                    score,
+                    ..user.clone()
+                })
+            })
+            .collect();
+        let any_changed = !changed.is_empty();
+        for user in changed {
+            self._set(user.username.clone(), user);
+        }
+        any_changed
+    }
 }
 
 #[derive(Debug)]
@@ -478,23 +3563,563 @@ impl DB {
         }
     }
 
-    fn set(&mut self, k: String, v: User) -> Result<(), Box<dyn Error>> {
-        self.db._set(k, v);
+    fn save(&self) -> Result<(), Box<dyn Error>> {
         // save serialized to disk
         let serialized = serde_json::to_string(&self.db)?;
         let mut fh = std::fs::OpenOptions::new()
             .create(true)
+            .truncate(true)
             .write(true)
             .open(&self.filename)?;
         fh.write_all(serialized.as_bytes())?;
+        Ok(())
+    }
+
+    fn set(&mut self, k: String, v: User) -> Result<(), Box<dyn Error>> {
+        if !self.db.map.contains_key(&k) {
+            add_dynamic_flag_owner(&k);
+        }
+        self.db._set(k, v);
+        self.save()?;
         // update scoreboard cache
         *SCOREBOARD_CACHE.lock().unwrap() = serde_json::to_string(&self.db.set)?;
         Ok(())
     }
 
+    // the scores of everyone may change with it, but the caches are refreshed only once.
+    // returns the bonus if the user is one of the first solvers
+    fn add_solve(
+        &mut self,
+        username: &str,
+        challenge_id: u16,
+    ) -> Result<Option<SolveBonus>, Box<dyn Error>> {
+        let rank = self
+            .db
+            ._solve_counts()
+            .get(&challenge_id)
+            .copied()
+            .unwrap_or(0)
+            + 1;
+        let bonus = find_challenge(challenge_id).and_then(|chal| chal.solve_bonus(rank));
+        let mut user = self.db._get(username).unwrap().clone();
+        user.solves.push(challenge_id);
+        user.bonuses.extend(bonus.clone());
+        self.db._set(username.to_string(), user);
+        self.db._recalculate_scores();
+        self.save()?;
+        *SCOREBOARD_CACHE.lock().unwrap() = serde_json::to_string(&self.db.set)?;
+        refresh_challenges_cache(&self.db)?;
+        Ok(bonus)
+    }
+
     fn get(&self, username: &str) -> Option<&User> {
         self.db._get(username)
     }
+
+    // charges the cost, which the caller checked the user can afford
+    fn unlock_hint(&mut self, username: &str, unlock: HintUnlock) -> Result<(), Box<dyn Error>> {
+        let mut user = self.get(username).unwrap().clone();
+        user.score -= i64::from(unlock.cost);
+        user.hint_unlocks.push(unlock);
+        self.set(username.to_string(), user)
+    }
+
+    // registered username which is the same or looks the same, if any
+    fn similar_username(&self, username: &str) -> Option<&str> {
+        let skeleton = username_skeleton(username);
+        self.db
+            .map
+            .keys()
+            .find(|k| username_skeleton(k) == skeleton)
+            .map(|k| k.as_str())
+    }
+
+    // compared case-insensitively, accounts without an address never clash
+    fn email_taken(&self, email: &str, except_username: &str) -> bool {
+        let email = email.to_lowercase();
+        self.db.map.values().any(|u| {
+            u.username != except_username && !u.email.is_empty() && u.email.to_lowercase() == email
+        })
+    }
+
+    // returns the token, which is only ever handed to the client
+    fn create_session(&mut self, username: &str, ip: IpAddr) -> Result<String, Box<dyn Error>> {
+        let now = get_unix_time();
+        // good moment to drop the sessions which expired in the meantime
+        self.db.sessions.retain(|_, s| s.expires > now);
+        let token = generate_token();
+        self.db.sessions.insert(
+            hash_token(&token),
+            Session {
+                username: username.to_string(),
+                created: now,
+                expires: now + SESSION_LIFETIME,
+                ip,
+            },
+        );
+        self.save()?;
+        Ok(token)
+    }
+
+    fn get_session_user(&self, token: &str) -> Option<&str> {
+        let session = self.db.sessions.get(&hash_token(token))?;
+        if session.expires <= get_unix_time() || self.get(&session.username).is_none() {
+            return None;
+        }
+        Some(&session.username)
+    }
+
+    fn is_session_valid(&self, username: &str, token: &str) -> bool {
+        match self.db.sessions.get(&hash_token(token)) {
+            Some(s) => s.username == username && s.expires > get_unix_time(),
+            None => false,
+        }
+    }
+
+    // username behind either an API token carrying the scope, or the session from the request body
+    fn authenticate(
+        &mut self,
+        headers: &HeaderMap,
+        username: &str,
+        auth_key: &str,
+        scope: ApiScope,
+    ) -> Result<String, AuthError> {
+        let username = match get_bearer_token(headers) {
+            Some(token) => self
+                .resolve_bearer(token, scope)
+                .map(BearerAuth::into_username),
+            None if self.is_session_valid(username, auth_key) => Some(username.to_string()),
+            None => None,
+        }
+        .ok_or(AuthError::Failed)?;
+        self.check_totp_setup(&username)?;
+        Ok(username)
+    }
+
+    // for the account endpoints, which only take the session
+    fn authenticate_session(&self, username: &str, auth_key: &str) -> Result<(), AuthError> {
+        if !self.is_session_valid(username, auth_key) {
+            return Err(AuthError::Failed);
+        }
+        self.check_totp_setup(username)
+    }
+
+    // accounts which have to enrol first can only reach the /2fa endpoints and /logout
+    fn check_totp_setup(&self, username: &str) -> Result<(), AuthError> {
+        match self.get(username) {
+            Some(user) if user.needs_totp_setup() => Err(AuthError::TotpSetupRequired),
+            _ => Ok(()),
+        }
+    }
+
+    // an `Authorization: Bearer` header means the same on every route: a session, or else an API token
+    fn resolve_bearer(&mut self, token: &str, scope: ApiScope) -> Option<BearerAuth> {
+        if let Some(username) = self.get_session_user(token) {
+            return Some(BearerAuth::Session(username.to_string()));
+        }
+        self.use_api_token(token, scope)
+            .unwrap()
+            .map(BearerAuth::ApiToken)
+    }
+
+    fn revoke_session(&mut self, token: &str) -> Result<(), Box<dyn Error>> {
+        self.db.sessions.remove(&hash_token(token));
+        self.save()
+    }
+
+    // API tokens go too, they are as good as a session for the endpoints they reach
+    // returns how many (sessions, API tokens) were revoked
+    fn revoke_user_sessions(&mut self, username: &str) -> Result<(usize, usize), Box<dyn Error>> {
+        let sessions = self.db.sessions.len();
+        let api_tokens = self.db.api_tokens.len();
+        self.db.sessions.retain(|_, s| s.username != username);
+        self.db.api_tokens.retain(|_, t| t.username != username);
+        self.save()?;
+        Ok((
+            sessions - self.db.sessions.len(),
+            api_tokens - self.db.api_tokens.len(),
+        ))
+    }
+
+    // a new request replaces any older token of the same user
+    fn create_password_reset(&mut self, username: &str) -> Result<String, Box<dyn Error>> {
+        let now = get_unix_time();
+        self.db
+            .password_resets
+            .retain(|_, r| r.expires > now && r.username != username);
+        let token = generate_token();
+        self.db.password_resets.insert(
+            hash_token(&token),
+            PasswordReset {
+                username: username.to_string(),
+                expires: now + PASSWORD_RESET_LIFETIME,
+            },
+        );
+        self.save()?;
+        Ok(token)
+    }
+
+    // tokens are single-use, a valid one is removed and its username returned
+    fn take_password_reset(&mut self, token: &str) -> Result<Option<String>, Box<dyn Error>> {
+        let reset = match self.db.password_resets.remove(&hash_token(token)) {
+            Some(reset) => reset,
+            None => return Ok(None),
+        };
+        self.save()?;
+        if reset.expires <= get_unix_time() {
+            return Ok(None);
+        }
+        Ok(Some(reset.username))
+    }
+
+    // counts a wrong password, returns the lockout duration if it locked the account
+    fn record_failed_login(&mut self, username: &str) -> Result<Option<u64>, Box<dyn Error>> {
+        let mut user = match self.get(username) {
+            Some(user) => user.clone(),
+            None => return Ok(None),
+        };
+        let mut locked_for = None;
+        user.failed_logins += 1;
+        if user.failed_logins >= LOCKOUT_THRESHOLD {
+            let duration =
+                LOCKOUT_DURATIONS[(user.lockouts as usize).min(LOCKOUT_DURATIONS.len() - 1)];
+            user.locked_until = get_unix_time() + duration;
+            user.lockouts += 1;
+            user.failed_logins = 0;
+            locked_for = Some(duration);
+        }
+        self.set(username.to_string(), user)?;
+        Ok(locked_for)
+    }
+
+    // only touches the file when there was something to clear
+    fn clear_lockout(&mut self, username: &str) -> Result<(), Box<dyn Error>> {
+        let mut user = match self.get(username) {
+            Some(user) => user.clone(),
+            None => return Ok(()),
+        };
+        if user.failed_logins == 0 && user.locked_until == 0 && user.lockouts == 0 {
+            return Ok(());
+        }
+        user.failed_logins = 0;
+        user.locked_until = 0;
+        user.lockouts = 0;
+        self.set(username.to_string(), user)
+    }
+
+    // starts (or restarts) the enrolment, returns the new base32 secret
+    fn begin_totp_setup(&mut self, username: &str) -> Result<String, Box<dyn Error>> {
+        let mut user = self.get(username).unwrap().clone();
+        let mut secret = [0u8; 20];
+        getrandom::getrandom(&mut secret).unwrap();
+        let secret = BASE32_NOPAD.encode(&secret);
+        user.totp = Some(Totp {
+            secret: secret.clone(),
+            enabled: false,
+            last_step: 0,
+            recovery_codes: Vec::new(),
+        });
+        self.set(username.to_string(), user)?;
+        Ok(secret)
+    }
+
+    // confirms the pending secret with a code, returns the plaintext recovery codes
+    fn enable_totp(
+        &mut self,
+        username: &str,
+        code: &str,
+    ) -> Result<Option<Vec<String>>, Box<dyn Error>> {
+        let mut user = self.get(username).unwrap().clone();
+        let totp = match user.totp.as_mut() {
+            Some(totp) if !totp.enabled => totp,
+            _ => return Ok(None),
+        };
+        let step = match check_totp(totp, code) {
+            Some(step) => step,
+            None => return Ok(None),
+        };
+        let codes: Vec<String> = (0..TOTP_RECOVERY_CODES)
+            .map(|_| generate_recovery_code())
+            .collect();
+        totp.enabled = true;
+        totp.last_step = step;
+        totp.recovery_codes = codes.iter().map(|c| hash_token(c)).collect();
+        self.set(username.to_string(), user)?;
+        Ok(Some(codes))
+    }
+
+    // accepts a current code from the app or one of the recovery codes, which is used up
+    fn verify_second_factor(&mut self, username: &str, code: &str) -> Result<bool, Box<dyn Error>> {
+        let mut user = match self.get(username) {
+            Some(user) => user.clone(),
+            None => return Ok(false),
+        };
+        let totp = match user.totp.as_mut() {
+            Some(totp) if totp.enabled => totp,
+            _ => return Ok(false),
+        };
+        let code_hash = hash_token(&code.trim().to_lowercase());
+        if let Some(step) = check_totp(totp, code) {
+            totp.last_step = step;
+        } else if let Some(i) = totp
+            .recovery_codes
+            .iter()
+            .position(|c| ct_eq(c, &code_hash))
+        {
+            totp.recovery_codes.remove(i);
+        } else {
+            return Ok(false);
+        }
+        self.set(username.to_string(), user)?;
+        Ok(true)
+    }
+
+    // returns false if there is no such user
+    fn set_role(&mut self, username: &str, role: Role) -> Result<bool, Box<dyn Error>> {
+        let mut user = match self.get(username) {
+            Some(user) => user.clone(),
+            None => return Ok(false),
+        };
+        user.role = role;
+        self.set(username.to_string(), user)?;
+        Ok(true)
+    }
+
+    fn disable_totp(&mut self, username: &str) -> Result<(), Box<dyn Error>> {
+        let mut user = self.get(username).unwrap().clone();
+        user.totp = None;
+        self.set(username.to_string(), user)
+    }
+
+    // returns the token, which is only ever shown to the user once
+    fn create_api_token(
+        &mut self,
+        username: &str,
+        name: &str,
+        scope: ApiScope,
+    ) -> Result<String, Box<dyn Error>> {
+        let token = format!("{API_TOKEN_PREFIX}{}", generate_token());
+        self.db.api_tokens.insert(
+            hash_token(&token),
+            ApiToken {
+                id: generate_token()[..16].to_string(),
+                username: username.to_string(),
+                name: name.to_string(),
+                scope,
+                created: get_unix_time(),
+                last_used: None,
+            },
+        );
+        self.save()?;
+        Ok(token)
+    }
+
+    fn get_api_tokens(&self, username: &str) -> Vec<&ApiToken> {
+        let mut tokens: Vec<&ApiToken> = self
+            .db
+            .api_tokens
+            .values()
+            .filter(|t| t.username == username)
+            .collect();
+        tokens.sort_by_key(|t| t.created);
+        tokens
+    }
+
+    fn revoke_api_token(&mut self, username: &str, id: &str) -> Result<bool, Box<dyn Error>> {
+        let before = self.db.api_tokens.len();
+        self.db
+            .api_tokens
+            .retain(|_, t| !(t.username == username && t.id == id));
+        if self.db.api_tokens.len() == before {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    // resolves a bearer token to its user, if the token carries the scope
+    fn use_api_token(
+        &mut self,
+        token: &str,
+        scope: ApiScope,
+    ) -> Result<Option<String>, Box<dyn Error>> {
+        let api_token = match self.db.api_tokens.get_mut(&hash_token(token)) {
+            Some(api_token) => api_token,
+            None => return Ok(None),
+        };
+        if scope == ApiScope::Submit && api_token.scope != ApiScope::Submit {
+            return Ok(None);
+        }
+        let username = api_token.username.to_owned();
+        let now = get_unix_time();
+        if api_token
+            .last_used
+            .is_none_or(|t| now - t >= API_TOKEN_LAST_USED_PRECISION)
+        {
+            api_token.last_used = Some(now);
+            self.save()?;
+        }
+        Ok(Some(username))
+    }
+
+    // returns the code, which admins hand out themselves
+    fn create_invite(&mut self, max_uses: u32, created_by: &str) -> Result<String, Box<dyn Error>> {
+        let code = generate_token()[..16].to_string();
+        self.db.invites.insert(
+            code.to_owned(),
+            Invite {
+                code: code.to_owned(),
+                max_uses,
+                uses: 0,
+                created: get_unix_time(),
+                created_by: created_by.to_string(),
+            },
+        );
+        self.save()?;
+        Ok(code)
+    }
+
+    fn get_invites(&self) -> Vec<&Invite> {
+        let mut invites: Vec<&Invite> = self.db.invites.values().collect();
+        invites.sort_by_key(|i| i.created);
+        invites
+    }
+
+    fn revoke_invite(&mut self, code: &str) -> Result<bool, Box<dyn Error>> {
+        if self.db.invites.remove(code).is_none() {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    fn record_sharing_incident(
+        &mut self,
+        challenge_id: u16,
+        username: &str,
+        owner: &str,
+        ip: IpAddr,
+    ) -> Result<(), Box<dyn Error>> {
+        self.db.sharing_incidents.push(SharingIncident {
+            time: get_unix_time(),
+            challenge_id,
+            username: username.to_string(),
+            owner: owner.to_string(),
+            ip,
+        });
+        self.save()
+    }
+
+    // newest first
+    fn get_sharing_incidents(&self) -> Vec<&SharingIncident> {
+        self.db.sharing_incidents.iter().rev().collect()
+    }
+
+    // why the code can't be used for a new account, if it can't
+    fn check_invite(&self, code: &str) -> Option<&'static str> {
+        if code.is_empty() {
+            return Some("Invite code required");
+        }
+        match self.db.invites.get(code.trim()) {
+            Some(invite) if invite.uses >= invite.max_uses => Some("Invite code has been used up"),
+            Some(_) => None,
+            None => Some("Invalid invite code"),
+        }
+    }
+
+    fn use_invite(&mut self, code: &str) -> Result<(), Box<dyn Error>> {
+        if let Some(invite) = self.db.invites.get_mut(code.trim()) {
+            invite.uses += 1;
+        }
+        self.save()
+    }
+
+    // account which linked this identity provider subject
+    fn oidc_subject_owner(&self, subject: &str) -> Option<&str> {
+        self.db
+            .map
+            .values()
+            .find(|u| u.oidc_subject.as_deref() == Some(subject))
+            .map(|u| u.username.as_str())
+    }
+
+    // why the identity can't get a new account, if it can't
+    fn oidc_signup_error(&self, claims: &IdTokenClaims) -> Option<&'static str> {
+        let email = claims.email.as_deref().filter(|_| claims.email_verified);
+        if email.is_some_and(|e| self.email_taken(e, "")) {
+            return Some("E-mail already registered, log in and link the account in the settings");
+        }
+        oidc_registration_error(claims)
+    }
+
+    // called for a logged in user only
+    fn link_oidc(
+        &mut self,
+        username: &str,
+        subject: &str,
+    ) -> Result<Result<(), &'static str>, Box<dyn Error>> {
+        if let Some(owner) = self.oidc_subject_owner(subject) {
+            return Ok(Err(if owner == username {
+                "Single sign-on account is already linked"
+            } else {
+                "Single sign-on account is linked to another account"
+            }));
+        }
+        let mut user = self.get(username).unwrap().clone();
+        user.oidc_subject = Some(subject.to_owned());
+        self.set(username.to_owned(), user)?;
+        Ok(Ok(()))
+    }
+
+    // account of an identity provider user, created on first login, None if it would have to be
+    // created but the registration mode (or an account with the same e-mail) doesn't allow it
+    fn oidc_user(&mut self, claims: &IdTokenClaims) -> Result<Option<String>, Box<dyn Error>> {
+        if let Some(username) = self.oidc_subject_owner(&claims.sub) {
+            return Ok(Some(username.to_owned()));
+        }
+        // an existing account is never taken over by a matching e-mail, it has to link itself
+        if self.oidc_signup_error(claims).is_some() {
+            return Ok(None);
+        }
+        let email = claims.email.as_deref().filter(|_| claims.email_verified);
+        // the provider has its own username rules, names which break ours get a generic one
+        let base = claims
+            .preferred_username
+            .as_deref()
+            .or(email.and_then(|e| e.split('@').next()))
+            .map(normalize_username)
+            .filter(|name| username_error(name).is_none())
+            .unwrap_or_else(|| "player".to_string());
+        let mut username = base.to_string();
+        let mut n = 2;
+        while self.similar_username(&username).is_some() {
+            username = format!("{base}{n}");
+            n += 1;
+        }
+        log_stdout(format!(
+            "OIDC account {GOLD}created{RESET} [username: {username}]"
+        ));
+        let u = User {
+            id: 0,
+            username: username.to_owned(),
+            email: email.unwrap_or_default().to_string(),
+            // empty hash, no password login until one is set through a reset
+            password: String::new(),
+            score: 0,
+            solves: Vec::new(),
+            bonuses: Vec::new(),
+            hint_unlocks: Vec::new(),
+            failed_logins: 0,
+            locked_until: 0,
+            lockouts: 0,
+            totp: None,
+            totp_required: false,
+            oidc_subject: Some(claims.sub.to_owned()),
+            role: Role::Player,
+        };
+        self.set(username.to_owned(), u)?;
+        Ok(Some(username))
+    }
 }
 
 #[tokio::main]
@@ -503,6 +4128,25 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     let database = Arc::new(Mutex::new(DB::new("./database.db")));
 
+    let args: Vec<String> = args().collect();
+    if args.get(1).is_some_and(|a| a == "set-role") {
+        set_role_command(&mut database.lock().unwrap(), &args).unwrap_or_else(|err| {
+            println!("Error: {err}");
+            std::process::exit(1);
+        });
+        return Ok(());
+    }
+
+    let state = Arc::new(AppState {
+        database: database.clone(),
+        login_limiter: RateLimiter::new(LOGIN_RATE_LIMIT),
+        reset_limiter: RateLimiter::new(PASSWORD_RESET_RATE_LIMIT),
+        flag_limiter: RateLimiter::new(FLAG_SUBMIT_RATE_LIMIT),
+        oidc_flows: Mutex::new(HashMap::new()),
+        pending_logins: Mutex::new(HashMap::new()),
+        pending_links: Mutex::new(HashMap::new()),
+    });
+
     let routes = Router::new()
         .route("/scoreboard", get(scoreboard))
         .route("/challenges", get(challenges));
@@ -510,26 +4154,74 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let db_routes = Router::new()
         .route("/flag_submit", post(flag_submit))
         .route("/profile", post(profile))
+        .route("/dynamic_flags", post(dynamic_flags))
+        .route("/hint_unlock", post(hint_unlock))
+        .route("/unlocked_hints", post(unlocked_hints))
         .route("/register", post(register))
         .route("/login", post(login))
-        .with_state(Arc::new(AppState {
-            database: database.clone(),
-        }));
+        .route("/logout", post(logout))
+        .route("/login/2fa", post(login_2fa))
+        .route("/oidc/login", get(oidc_login))
+        .route("/oidc/callback", get(oidc_callback))
+        .route("/oidc/link", post(oidc_link))
+        .route("/oidc/link/confirm", post(oidc_link_confirm))
+        .route("/2fa/setup", post(totp_setup))
+        .route("/2fa/enable", post(totp_enable))
+        .route("/2fa/disable", post(totp_disable))
+        .route("/api_tokens", post(api_tokens))
+        .route("/api_tokens/create", post(api_token_create))
+        .route("/api_tokens/revoke", post(api_token_revoke))
+        .route("/change_password", post(change_password))
+        .route("/change_email", post(change_email))
+        .route("/forgot_password", post(forgot_password))
+        .route("/reset_password", post(reset_password))
+        .with_state(state.clone());
 
-    // TODO: change_pass POST endpoint
-    let app = Router::new()
+    let author_routes = Router::new()
+        .route("/author/challenges", post(author_challenges))
+        .route_layer(from_fn_with_state(
+            (state.clone(), Role::Author),
+            require_role,
+        ))
+        .with_state(state.clone());
+
+    let admin_routes = Router::new()
+        .route("/admin/logout_everywhere", post(admin_logout_everywhere))
+        .route("/admin/unlock", post(admin_unlock))
+        .route("/admin/require_2fa", post(admin_require_2fa))
+        .route("/admin/set_role", post(admin_set_role))
+        .route("/admin/invites", post(admin_invites))
+        .route("/admin/invites/create", post(admin_invite_create))
+        .route("/admin/invites/revoke", post(admin_invite_revoke))
+        .route("/admin/audit", post(admin_audit))
+        .route("/admin/audit/export", post(admin_audit_export))
+        .route("/admin/sharing_incidents", post(admin_sharing_incidents))
+        .route_layer(from_fn_with_state(
+            (state.clone(), Role::Admin),
+            require_role,
+        ))
+        .with_state(state);
+
+    let mut app = Router::new()
         .merge(routes)
         .merge(db_routes)
-        .layer(
-            CorsLayer::new() // I would love to get rid of this (for a substantial performance boost) but the browser won't let me do that
-                .allow_methods([Method::GET, Method::POST])
-                .allow_headers(Any)
-                .allow_origin(Any),
-        )
+        .merge(author_routes)
+        .merge(admin_routes);
+    if let Some(cors) = cors_layer()? {
+        app = app.layer(cors);
+    }
+    let app = app
+        .layer(from_fn(security_headers))
         .layer(from_fn(log_requests)); // uncomment for request logging. comment for better perf
 
     initialize_challenges()?;
+    initialize_security_headers()?;
+    initialize_scores(&mut database.lock().unwrap())?;
+    refresh_challenges_cache(&database.lock().unwrap().db)?;
     initialize_scoreboard_cache(&database.lock().unwrap().db.set)?;
+    initialize_flag_secret(&mut database.lock().unwrap())?;
+    initialize_dynamic_flag_owners(&database.lock().unwrap());
+    initialize_admins(&mut database.lock().unwrap())?;
 
     println!("Starting {GOLD}backend{RESET} on: {GOLD}{bind_addr}{RESET}");
 
@@ -539,3 +4231,853 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::HttpBody;
+    use std::sync::Once;
+    use tower::util::ServiceExt;
+
+    // every kind of flag, plus a decaying challenge with a paid hint
+    const TEST_CHALLENGES: &str = r#"[{"name": "test", "challenges": [
+        {"id": 0, "name": "decaying", "description": "",
+         "hints": [{"text": "expensive", "cost": 90}],
+         "scoring": {"function": "linear", "initial": 100, "minimum": 20, "decay": 2},
+         "flag": "flag{test}"},
+        {"id": 1, "name": "static", "description": "", "points": 100, "flag": "flag{static}",
+         "first_blood_bonus": [10, 5]},
+        {"id": 2, "name": "case insensitive", "description": "", "points": 100,
+         "flag": {"type": "static_ci", "value": "flag{MiXeD}", "trim": true}},
+        {"id": 3, "name": "regex", "description": "", "points": 100,
+         "flag": {"type": "regex", "value": "flag\\{[0-9]{3}\\}"}},
+        {"id": 4, "name": "dynamic", "description": "{{flag}}", "points": 100,
+         "flag": [{"type": "dynamic", "value": "dyn"}, "flag{fallback}"]}
+    ]}]"#;
+
+    // the statics can only be set once per test binary, and the tests run in parallel
+    fn init_test_statics() {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            CHALLENGES_JSON
+                .set(serde_json::from_str(TEST_CHALLENGES).unwrap())
+                .unwrap();
+            FLAG_SECRET.set("test secret".to_string()).unwrap();
+        });
+    }
+
+    // a database file for every test
+    fn test_db_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("backend_test_{}_{name}.db", std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    fn test_state(db: DB) -> Arc<AppState> {
+        Arc::new(AppState {
+            database: Arc::new(Mutex::new(db)),
+            login_limiter: RateLimiter::new(LOGIN_RATE_LIMIT),
+            reset_limiter: RateLimiter::new(PASSWORD_RESET_RATE_LIMIT),
+            flag_limiter: RateLimiter::new(FLAG_SUBMIT_RATE_LIMIT),
+            oidc_flows: Mutex::new(HashMap::new()),
+            pending_logins: Mutex::new(HashMap::new()),
+            pending_links: Mutex::new(HashMap::new()),
+        })
+    }
+
+    async fn body_string(res: Response) -> String {
+        let mut body = res.into_body();
+        let mut out = Vec::new();
+        while let Some(chunk) = body.data().await {
+            out.extend_from_slice(&chunk.unwrap());
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn ct_eq_matches_string_equality() {
+        assert!(ct_eq("flag{chal}", "flag{chal}"));
+        assert!(ct_eq("", ""));
+        assert!(!ct_eq("flag{chal}", "flag{chaL}"));
+        assert!(!ct_eq("flag{chal}", "flag{chal}}"));
+        assert!(!ct_eq("flag{chal}", "flag{"));
+        assert!(!ct_eq("flag{chal}", ""));
+    }
+
+    #[tokio::test]
+    async fn flag_submit_rejects_bad_auth_keys_and_wrong_flags() {
+        init_test_statics();
+        let filename = test_db_path("flag_submit");
+        let mut db = DB::new(&filename);
+        db.set("player".to_string(), test_user("player", Role::Player))
+            .unwrap();
+        let auth_key = db
+            .create_session("player", "127.0.0.1".parse().unwrap())
+            .unwrap();
+        let state = test_state(db);
+        let app = Router::new()
+            .route("/flag_submit", post(flag_submit))
+            .with_state(state.clone());
+
+        let submit = |auth_key: &str, flag: &str| {
+            let mut req = Request::builder()
+                .method(Method::POST)
+                .uri("/flag_submit")
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    serde_json::json!({
+                        "username": "player",
+                        "auth_key": auth_key,
+                        "challenge_id": 1,
+                        "flag": flag,
+                    })
+                    .to_string(),
+                ))
+                .unwrap();
+            req.extensions_mut()
+                .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 4000))));
+            let app = app.clone();
+            async move { body_string(app.oneshot(req).await.unwrap()).await }
+        };
+        let solves = || {
+            state
+                .database
+                .lock()
+                .unwrap()
+                .get("player")
+                .unwrap()
+                .solves
+                .clone()
+        };
+
+        assert_eq!(
+            submit("", "flag{static}").await,
+            "{\"status\":\"FAIL\",\"cause\":\"You need to be logged in to submit flags\"}"
+        );
+        // same length as a real key, so only the comparison itself can tell them apart
+        assert_eq!(
+            submit(&"0".repeat(auth_key.len()), "flag{static}").await,
+            "{\"status\":\"FAIL\",\"cause\":\"Authentication failed\"}"
+        );
+        assert_eq!(
+            submit(&auth_key, "flag{wrong}").await,
+            "{\"status\":\"FAIL\",\"cause\":\"Wrong flag\"}"
+        );
+        assert!(solves().is_empty());
+        assert_eq!(
+            submit(&auth_key, "flag{static}").await,
+            "{\"status\":\"OK\"}"
+        );
+        assert_eq!(solves(), vec![1]);
+
+        let _ = std::fs::remove_file(filename);
+    }
+
+    fn test_user(username: &str, role: Role) -> User {
+        User {
+            id: 0,
+            username: username.to_string(),
+            email: format!("{username}@example.com"),
+            password: String::new(),
+            score: 0,
+            solves: Vec::new(),
+            bonuses: Vec::new(),
+            hint_unlocks: Vec::new(),
+            failed_logins: 0,
+            locked_until: 0,
+            lockouts: 0,
+            totp: None,
+            totp_required: false,
+            oidc_subject: None,
+            role,
+        }
+    }
+
+    #[tokio::test]
+    async fn require_role_rejects_players_on_admin_routes() {
+        init_test_statics();
+        let filename = test_db_path("require_role");
+        let mut db = DB::new(&filename);
+        let ip = "127.0.0.1".parse().unwrap();
+        let mut sessions = Vec::new();
+        for (username, role) in [("player", Role::Player), ("admin", Role::Admin)] {
+            db.set(username.to_string(), test_user(username, role))
+                .unwrap();
+            sessions.push(db.create_session(username, ip).unwrap());
+        }
+        let state = test_state(db);
+        let app = Router::new()
+            .route("/admin/sharing_incidents", post(admin_sharing_incidents))
+            .route_layer(from_fn_with_state(
+                (state.clone(), Role::Admin),
+                require_role,
+            ))
+            .with_state(state);
+
+        let status = |auth_key: &str| {
+            let req = Request::builder()
+                .method(Method::POST)
+                .uri("/admin/sharing_incidents")
+                .header(AUTHORIZATION, format!("Bearer {auth_key}"))
+                .body(Body::empty())
+                .unwrap();
+            let app = app.clone();
+            async move { app.oneshot(req).await.unwrap().status() }
+        };
+        assert_eq!(status(&sessions[0]).await, StatusCode::FORBIDDEN);
+        assert_eq!(status("not-a-session").await, StatusCode::FORBIDDEN);
+        assert_eq!(status(&sessions[1]).await, StatusCode::OK);
+
+        let _ = std::fs::remove_file(filename);
+    }
+
+    #[test]
+    fn recalculation_keeps_hint_costs_after_decay() {
+        // challenge 0 is worth 100 for the first solver, 60 once there are two
+        init_test_statics();
+        let mut db = DBInner::_new();
+        let mut buyer = test_user("buyer", Role::Player);
+        buyer.solves.push(0);
+        buyer.hint_unlocks.push(HintUnlock {
+            challenge_id: 0,
+            hint: 0,
+            cost: 90,
+        });
+        db._set("buyer".to_string(), buyer);
+        db._recalculate_scores();
+        assert_eq!(db._get("buyer").unwrap().score, 10);
+
+        let mut other = test_user("other", Role::Player);
+        other.solves.push(0);
+        db._set("other".to_string(), other);
+        db._recalculate_scores();
+        assert_eq!(db._get("other").unwrap().score, 60);
+        assert_eq!(db._get("buyer").unwrap().score, -30);
+    }
+
+    fn scoring(function: DecayFunction, initial: u32, minimum: u32, decay: u32) -> Scoring {
+        Scoring {
+            function,
+            initial,
+            minimum,
+            decay,
+        }
+    }
+
+    #[test]
+    fn linear_scoring_loses_the_same_amount_per_solve() {
+        let s = scoring(DecayFunction::Linear, 500, 100, 20);
+        assert_eq!(s.value(0), 500);
+        assert_eq!(s.value(1), 500);
+        assert_eq!(s.value(2), 480);
+        assert_eq!(s.value(11), 300);
+        assert_eq!(s.value(20), 120);
+        assert_eq!(s.value(21), 100);
+        assert_eq!(s.value(1000), 100);
+        // rounded up, in favour of the solvers
+        assert_eq!(scoring(DecayFunction::Linear, 100, 0, 3).value(2), 67);
+    }
+
+    #[test]
+    fn logarithmic_scoring_is_slow_at_first_then_faster() {
+        let s = scoring(DecayFunction::Logarithmic, 500, 100, 20);
+        assert_eq!(s.value(1), 500);
+        assert_eq!(s.value(2), 499);
+        assert_eq!(s.value(11), 400);
+        assert_eq!(s.value(20), 139);
+        assert_eq!(s.value(21), 100);
+        assert_eq!(s.value(1000), 100);
+    }
+
+    #[test]
+    fn scoring_edge_cases() {
+        // the second solver already brings it down to the minimum
+        let s = scoring(DecayFunction::Linear, 500, 100, 1);
+        assert_eq!((s.value(1), s.value(2)), (500, 100));
+        // nothing to lose
+        let s = scoring(DecayFunction::Logarithmic, 300, 300, 10);
+        assert_eq!((s.value(1), s.value(5), s.value(50)), (300, 300, 300));
+    }
+
+    #[test]
+    fn scoring_config_errors() {
+        assert!(scoring(DecayFunction::Linear, 500, 100, 20)
+            .config_error()
+            .is_none());
+        assert!(scoring(DecayFunction::Linear, 100, 500, 20)
+            .config_error()
+            .is_some());
+        assert!(scoring(DecayFunction::Logarithmic, 500, 100, 0)
+            .config_error()
+            .is_some());
+    }
+
+    #[test]
+    fn solve_bonus_is_a_percentage_of_the_value_at_that_rank() {
+        let chal: Challenge = serde_json::from_str(
+            r#"{"id": 7, "name": "", "description": "", "flag": "flag{x}",
+                "first_blood_bonus": [10, 5, 2],
+                "scoring": {"function": "linear", "initial": 200, "minimum": 100, "decay": 10}}"#,
+        )
+        .unwrap();
+        let points = |rank| chal.solve_bonus(rank).map(|b| b.points);
+        assert_eq!(points(1), Some(20));
+        // 190 by the time of the second solve
+        assert_eq!(points(2), Some(9));
+        assert_eq!(points(3), Some(3));
+        assert_eq!(points(4), None);
+        assert_eq!(points(0), None);
+        assert_eq!(chal.solve_bonus(1).unwrap().title(), "First blood");
+        assert_eq!(chal.solve_bonus(2).unwrap().title(), "Early solve #2");
+    }
+
+    #[test]
+    fn add_solve_hands_out_the_bonuses_in_order() {
+        init_test_statics();
+        let filename = test_db_path("add_solve");
+        let mut db = DB::new(&filename);
+        for username in ["first", "second", "third"] {
+            db.set(username.to_string(), test_user(username, Role::Player))
+                .unwrap();
+        }
+        let ranks: Vec<Option<u32>> = ["first", "second", "third"]
+            .iter()
+            .map(|u| db.add_solve(u, 1).unwrap().map(|b| b.rank))
+            .collect();
+        assert_eq!(ranks, vec![Some(1), Some(2), None]);
+        let score = |u| db.get(u).unwrap().score;
+        assert_eq!(
+            (score("first"), score("second"), score("third")),
+            (110, 105, 100)
+        );
+
+        let _ = std::fs::remove_file(filename);
+    }
+
+    fn flag_spec(json: &str) -> FlagSpec {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn static_flags_match_exactly() {
+        let spec = flag_spec(r#""flag{static}""#);
+        assert!(spec.accepts("flag{static}", 1, "player"));
+        assert!(!spec.accepts("FLAG{static}", 1, "player"));
+        assert!(!spec.accepts(" flag{static}", 1, "player"));
+        assert!(!spec.accepts("", 1, "player"));
+    }
+
+    #[test]
+    fn case_insensitive_flags_can_be_trimmed() {
+        let spec = flag_spec(r#"{"type": "static_ci", "value": "flag{MiXeD}", "trim": true}"#);
+        assert!(spec.accepts("flag{mixed}", 2, "player"));
+        assert!(spec.accepts("  FLAG{MIXED}\n", 2, "player"));
+        assert!(!spec.accepts("flag{mixe}", 2, "player"));
+    }
+
+    #[test]
+    fn regex_flags_match_the_whole_submission() {
+        let spec = flag_spec(r#"{"type": "regex", "value": "flag\\{[0-9]{3}\\}"}"#);
+        assert!(spec.accepts("flag{123}", 3, "player"));
+        assert!(!spec.accepts("flag{1234}", 3, "player"));
+        assert!(!spec.accepts("xflag{123}", 3, "player"));
+        // alternatives stay inside the anchors
+        let spec = flag_spec(r#"{"type": "regex", "value": "flag\\{a\\}|flag\\{b\\}"}"#);
+        assert!(spec.accepts("flag{b}", 3, "player"));
+        assert!(!spec.accepts("flag{b}x", 3, "player"));
+    }
+
+    #[test]
+    fn any_of_several_flags_is_accepted() {
+        let spec = flag_spec(r#"["flag{one}", {"type": "static_ci", "value": "flag{two}"}]"#);
+        assert!(spec.accepts("flag{one}", 1, "player"));
+        assert!(spec.accepts("FLAG{TWO}", 1, "player"));
+        assert!(!spec.accepts("flag{three}", 1, "player"));
+    }
+
+    #[test]
+    fn broken_flag_configs_are_rejected() {
+        assert!(
+            serde_json::from_str::<FlagSpec>(r#"{"type": "regex", "value": "flag{("}"#).is_err()
+        );
+        assert!(serde_json::from_str::<FlagSpec>(r#"{"type": "glob", "value": "flag*"}"#).is_err());
+        assert!(serde_json::from_str::<FlagSpec>("42").is_err());
+    }
+
+    #[test]
+    fn dynamic_flags_are_per_player_and_per_challenge() {
+        init_test_statics();
+        let spec = &find_challenge(4).unwrap().flag;
+        let flag = spec.dynamic_flag(4, "alice").unwrap();
+        let hex = flag
+            .strip_prefix("dyn{")
+            .unwrap()
+            .strip_suffix('}')
+            .unwrap();
+        assert_eq!(hex.len(), 32);
+        assert!(hex
+            .bytes()
+            .all(|b| b.is_ascii_hexdigit() && !b.is_ascii_uppercase()));
+        assert_eq!(spec.dynamic_flag(4, "alice").unwrap(), flag);
+        assert_ne!(spec.dynamic_flag(4, "bob").unwrap(), flag);
+        assert_ne!(dynamic_flag("dyn", 5, "alice"), flag);
+        assert!(spec.accepts(&flag, 4, "alice"));
+        assert!(!spec.accepts(&flag, 4, "bob"));
+        // the fallback rule still works for everyone
+        assert!(spec.accepts("flag{fallback}", 4, "bob"));
+        assert_eq!(
+            find_challenge(1).unwrap().flag.dynamic_flag(1, "alice"),
+            None
+        );
+    }
+
+    #[test]
+    fn shared_dynamic_flags_are_traced_to_their_owner() {
+        init_test_statics();
+        add_dynamic_flag_owner("flag_owner_test");
+        let flag = dynamic_flag("dyn", 4, "flag_owner_test");
+        assert_eq!(
+            dynamic_flag_owner(4, &flag).as_deref(),
+            Some("flag_owner_test")
+        );
+        assert_eq!(
+            dynamic_flag_owner(4, &format!(" {flag}\n")).as_deref(),
+            Some("flag_owner_test")
+        );
+        assert_eq!(dynamic_flag_owner(1, &flag), None);
+        assert_eq!(
+            dynamic_flag_owner(4, "dyn{00000000000000000000000000000000}"),
+            None
+        );
+        assert_eq!(dynamic_flag_owner(4, "flag{fallback}"), None);
+    }
+
+    #[test]
+    fn new_users_get_their_dynamic_flags_registered() {
+        init_test_statics();
+        let filename = test_db_path("flag_owners");
+        let mut db = DB::new(&filename);
+        db.set(
+            "late_registration".to_string(),
+            test_user("late_registration", Role::Player),
+        )
+        .unwrap();
+        let flag = dynamic_flag("dyn", 4, "late_registration");
+        assert_eq!(
+            dynamic_flag_owner(4, &flag).as_deref(),
+            Some("late_registration")
+        );
+        let _ = std::fs::remove_file(filename);
+    }
+
+    #[test]
+    fn totp_codes_match_the_rfc_6238_vectors() {
+        // the SHA1 secret of RFC 6238 appendix B, truncated to 6 digits
+        let secret = b"12345678901234567890";
+        for (time, code) in [
+            (59, 287082),
+            (1111111109, 81804),
+            (1111111111, 50471),
+            (1234567890, 5924),
+            (2000000000, 279037),
+        ] {
+            assert_eq!(totp_code(secret, time / TOTP_STEP), code, "T = {time}");
+        }
+    }
+
+    fn current_totp_code(totp: &Totp) -> String {
+        let secret = BASE32_NOPAD.decode(totp.secret.as_bytes()).unwrap();
+        format!("{:06}", totp_code(&secret, get_unix_time() / TOTP_STEP))
+    }
+
+    #[test]
+    fn totp_codes_work_only_once() {
+        let mut totp = Totp {
+            secret: BASE32_NOPAD.encode(b"12345678901234567890"),
+            enabled: true,
+            last_step: 0,
+            recovery_codes: Vec::new(),
+        };
+        let code = current_totp_code(&totp);
+        let step = check_totp(&totp, &code).unwrap();
+        assert!(step.abs_diff(get_unix_time() / TOTP_STEP) <= TOTP_SKEW);
+        assert_eq!(check_totp(&totp, &format!(" {code}\n")), Some(step));
+        totp.last_step = step;
+        assert_eq!(check_totp(&totp, &code), None);
+        assert_eq!(check_totp(&totp, "12345"), None);
+        assert_eq!(check_totp(&totp, "abcdef"), None);
+    }
+
+    #[test]
+    fn second_factor_accepts_each_recovery_code_once() {
+        init_test_statics();
+        let filename = test_db_path("second_factor");
+        let mut db = DB::new(&filename);
+        db.set("player".to_string(), test_user("player", Role::Player))
+            .unwrap();
+        db.begin_totp_setup("player").unwrap();
+        // a second factor that isn't enabled yet doesn't count
+        let code = current_totp_code(db.get("player").unwrap().totp.as_ref().unwrap());
+        assert!(!db.verify_second_factor("player", &code).unwrap());
+        assert_eq!(db.enable_totp("player", "000000x").unwrap(), None);
+        let recovery_codes = db.enable_totp("player", &code).unwrap().unwrap();
+        assert_eq!(recovery_codes.len(), TOTP_RECOVERY_CODES);
+        // the code that enabled it is already used up
+        assert!(!db.verify_second_factor("player", &code).unwrap());
+        let recovery_code = &recovery_codes[0];
+        assert!(db
+            .verify_second_factor("player", &recovery_code.to_uppercase())
+            .unwrap());
+        assert!(!db.verify_second_factor("player", recovery_code).unwrap());
+        assert!(db
+            .verify_second_factor("player", &recovery_codes[1])
+            .unwrap());
+        let totp = db.get("player").unwrap().totp.clone().unwrap();
+        assert_eq!(totp.recovery_codes.len(), TOTP_RECOVERY_CODES - 2);
+        assert!(!db.verify_second_factor("nobody", recovery_code).unwrap());
+        let _ = std::fs::remove_file(filename);
+    }
+
+    #[test]
+    fn rate_limiter_allows_a_burst_then_refills() {
+        let limiter = RateLimiter::new(RateLimit {
+            burst: 3,
+            per_minute: 1,
+        });
+        let keys = ["ip:10.0.0.1".to_string(), "user:player".to_string()];
+        for _ in 0..3 {
+            assert!(limiter.check(&keys));
+        }
+        assert!(!limiter.check(&keys));
+        // an empty bucket blocks every request sharing that key, without draining the others
+        let other_ip = ["ip:10.0.0.2".to_string(), "user:player".to_string()];
+        assert!(!limiter.check(&other_ip));
+        for _ in 0..3 {
+            assert!(limiter.check(&other_ip[..1]));
+        }
+        assert!(limiter.check(&["user:someone_else".to_string()]));
+        // a minute later one token is back
+        for (_, last) in limiter.buckets.lock().unwrap().values_mut() {
+            *last -= Duration::from_secs(60);
+        }
+        assert!(limiter.check(&keys));
+        assert!(!limiter.check(&keys));
+    }
+
+    // wrong passwords until the account locks, returns how long for
+    fn lock_account(db: &mut DB, username: &str) -> Option<u64> {
+        for _ in 1..LOCKOUT_THRESHOLD {
+            assert_eq!(db.record_failed_login(username).unwrap(), None);
+        }
+        db.record_failed_login(username).unwrap()
+    }
+
+    #[test]
+    fn lockouts_grow_until_a_successful_login() {
+        init_test_statics();
+        let filename = test_db_path("lockout");
+        let mut db = DB::new(&filename);
+        db.set("player".to_string(), test_user("player", Role::Player))
+            .unwrap();
+        let durations: Vec<Option<u64>> = (0..LOCKOUT_DURATIONS.len() + 1)
+            .map(|_| lock_account(&mut db, "player"))
+            .collect();
+        assert_eq!(
+            durations,
+            [5 * 60, 30 * 60, 2 * 60 * 60, 24 * 60 * 60, 24 * 60 * 60].map(Some)
+        );
+        let user = db.get("player").unwrap();
+        assert!(user.locked_until >= get_unix_time() + 24 * 60 * 60 - 1);
+        assert_eq!(user.failed_logins, 0);
+        assert_eq!(user.lockouts, 5);
+
+        db.clear_lockout("player").unwrap();
+        let user = db.get("player").unwrap();
+        assert_eq!(
+            (user.failed_logins, user.locked_until, user.lockouts),
+            (0, 0, 0)
+        );
+        assert_eq!(lock_account(&mut db, "player"), Some(5 * 60));
+        assert_eq!(db.record_failed_login("nobody").unwrap(), None);
+        let _ = std::fs::remove_file(filename);
+    }
+
+    #[test]
+    fn usernames_are_normalized_and_validated() {
+        assert_eq!(normalize_username("  player\n"), "player");
+        // fullwidth letters are folded by NFKC
+        assert_eq!(normalize_username("\u{ff50}layer"), "player");
+        assert_eq!(username_error("player_1.x-y"), None);
+        assert!(username_error(&"a".repeat(USERNAME_MIN_LENGTH - 1)).is_some());
+        assert_eq!(username_error(&"a".repeat(USERNAME_MIN_LENGTH)), None);
+        assert_eq!(username_error(&"a".repeat(USERNAME_MAX_LENGTH)), None);
+        assert!(username_error(&"a".repeat(USERNAME_MAX_LENGTH + 1)).is_some());
+        // the length counts characters, not bytes
+        assert_eq!(
+            username_error(&"ü".repeat(USERNAME_MAX_LENGTH)).is_some(),
+            USERNAME_ASCII_ONLY
+        );
+        for name in ["two words", "<script>", "tab\tname", "admin\u{200b}"] {
+            assert!(username_error(name).is_some(), "{name:?}");
+        }
+    }
+
+    #[test]
+    fn lookalike_usernames_are_found() {
+        init_test_statics();
+        assert_eq!(username_skeleton("admin"), username_skeleton("\u{430}dmin"));
+        assert_eq!(username_skeleton("Admin"), username_skeleton("admin"));
+        assert_ne!(username_skeleton("admin"), username_skeleton("admins"));
+        let filename = test_db_path("similar_username");
+        let mut db = DB::new(&filename);
+        db.set("admin".to_string(), test_user("admin", Role::Player))
+            .unwrap();
+        assert_eq!(db.similar_username("\u{430}dmin"), Some("admin"));
+        assert_eq!(db.similar_username("ADMIN"), Some("admin"));
+        assert_eq!(db.similar_username("player"), None);
+        let _ = std::fs::remove_file(filename);
+    }
+
+    #[test]
+    fn email_addresses_are_checked_loosely() {
+        for email in [
+            "player@example.com",
+            "first.last+ctf@sub.example.co.uk",
+            "ü@bücher.de",
+        ] {
+            assert_eq!(email_error(email), None, "{email}");
+        }
+        for email in [
+            "",
+            "player",
+            "@example.com",
+            "player@localhost",
+            "player@example..com",
+            "player@-example.com",
+            "a@b@example.com",
+            "two words@example.com",
+            "<player>@example.com",
+        ] {
+            assert!(email_error(email).is_some(), "{email}");
+        }
+        assert!(email_error(&format!("{}@example.com", "a".repeat(65))).is_some());
+    }
+
+    #[test]
+    fn taken_emails_ignore_case_and_the_own_account() {
+        init_test_statics();
+        let filename = test_db_path("email_taken");
+        let mut db = DB::new(&filename);
+        db.set("player".to_string(), test_user("player", Role::Player))
+            .unwrap();
+        let mut no_email = test_user("no_email", Role::Player);
+        no_email.email = String::new();
+        db.set("no_email".to_string(), no_email).unwrap();
+        assert!(db.email_taken("player@example.com", "other"));
+        assert!(db.email_taken("Player@EXAMPLE.com", "other"));
+        assert!(!db.email_taken("player@example.com", "player"));
+        assert!(!db.email_taken("other@example.com", "other"));
+        assert!(!db.email_taken("", "other"));
+        let _ = std::fs::remove_file(filename);
+    }
+
+    #[test]
+    fn invite_codes_are_used_up() {
+        init_test_statics();
+        let filename = test_db_path("invites");
+        let mut db = DB::new(&filename);
+        let single = db.create_invite(1, "admin").unwrap();
+        let multi = db.create_invite(2, "admin").unwrap();
+        assert_eq!(db.check_invite(&single), None);
+        db.use_invite(&single).unwrap();
+        assert_eq!(
+            db.check_invite(&single),
+            Some("Invite code has been used up")
+        );
+        // surrounding whitespace from copy and paste is fine
+        assert_eq!(db.check_invite(&format!(" {multi}\n")), None);
+        db.use_invite(&format!(" {multi}\n")).unwrap();
+        assert_eq!(db.check_invite(&multi), None);
+        db.use_invite(&multi).unwrap();
+        assert_eq!(
+            db.check_invite(&multi),
+            Some("Invite code has been used up")
+        );
+
+        let revoked = db.create_invite(5, "admin").unwrap();
+        assert!(db.revoke_invite(&revoked).unwrap());
+        assert!(!db.revoke_invite(&revoked).unwrap());
+        assert_eq!(db.check_invite(&revoked), Some("Invalid invite code"));
+        assert_eq!(db.check_invite("not a code"), Some("Invalid invite code"));
+        assert_eq!(db.check_invite(""), Some("Invite code required"));
+        let _ = std::fs::remove_file(filename);
+    }
+
+    #[test]
+    fn passwords_verify_against_argon2_and_legacy_hashes() {
+        let legacy = hash(b"Password123!").to_hex().to_string();
+        assert!(is_legacy_hash(&legacy));
+        assert!(verify_password("Password123!", &legacy));
+        assert!(!verify_password("password123!", &legacy));
+
+        let argon = hash_password("Password123!");
+        assert!(argon.starts_with("$argon2id$"));
+        assert!(!is_legacy_hash(&argon));
+        assert!(verify_password("Password123!", &argon));
+        assert!(!verify_password("password123!", &argon));
+        // salted, so the same password never hashes the same twice
+        assert_ne!(hash_password("Password123!"), argon);
+        assert!(!verify_password("Password123!", "$argon2id$garbage"));
+    }
+
+    #[tokio::test]
+    async fn login_migrates_legacy_password_hashes() {
+        init_test_statics();
+        let filename = test_db_path("legacy_login");
+        let mut db = DB::new(&filename);
+        let mut user = test_user("player", Role::Player);
+        user.password = hash(b"Password123!").to_hex().to_string();
+        db.set("player".to_string(), user).unwrap();
+        let state = test_state(db);
+        let app = Router::new()
+            .route("/login", post(login))
+            .with_state(state.clone());
+
+        let login = |password: &str| {
+            let mut req = Request::builder()
+                .method(Method::POST)
+                .uri("/login")
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    serde_json::json!({ "username": "player", "password": password }).to_string(),
+                ))
+                .unwrap();
+            req.extensions_mut()
+                .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 4000))));
+            let app = app.clone();
+            async move { body_string(app.oneshot(req).await.unwrap()).await }
+        };
+        let stored_hash = || {
+            state
+                .database
+                .lock()
+                .unwrap()
+                .get("player")
+                .unwrap()
+                .password
+                .clone()
+        };
+
+        // a wrong password leaves the old hash alone
+        assert!(login("wrong").await.contains("FAIL"));
+        assert!(is_legacy_hash(&stored_hash()));
+        assert!(login("Password123!").await.contains("\"status\":\"OK\""));
+        let migrated = stored_hash();
+        assert!(!is_legacy_hash(&migrated));
+        assert!(verify_password("Password123!", &migrated));
+        assert!(login("Password123!").await.contains("\"status\":\"OK\""));
+        assert_eq!(stored_hash(), migrated);
+        let _ = std::fs::remove_file(filename);
+    }
+
+    #[test]
+    fn sensitive_values_are_redacted_in_logs() {
+        let show = |value: &str, redaction| Sensitive(value, redaction).to_string();
+        assert_eq!(
+            show("player@example.edu", Redaction::Plain),
+            "player@example.edu"
+        );
+        assert_eq!(
+            show("player@example.edu", Redaction::Mask),
+            "p***@example.edu"
+        );
+        assert_eq!(show("player", Redaction::Mask), "p***");
+        assert_eq!(show("", Redaction::Mask), "***");
+        assert_eq!(show("player", Redaction::Hidden), "[redacted]");
+        let hashed = show("player", Redaction::Hash);
+        assert_eq!(hashed.len(), 13);
+        assert!(hashed.starts_with('#'));
+        assert!(!hashed.contains("player"));
+        assert_eq!(show("player", Redaction::Hash), hashed);
+        assert_ne!(show("player2", Redaction::Hash), hashed);
+    }
+
+    #[test]
+    fn secrets_in_request_urls_are_redacted() {
+        let redact = |uri: &str| redact_uri(&uri.parse().unwrap());
+        assert_eq!(redact("/scoreboard"), "/scoreboard");
+        assert_eq!(
+            redact("/reset_password?token=0123abcd&next=/"),
+            format!(
+                "/reset_password?token={}&next=/",
+                Sensitive("0123abcd", LOG_QUERY_SECRETS)
+            )
+        );
+        assert!(!redact("/oidc/callback?state=s3cret&code=c0de").contains("s3cret"));
+        assert!(!redact("/oidc/callback?state=s3cret&code=c0de").contains("c0de"));
+    }
+
+    fn id_token_claims(subject: &str, email: &str, email_verified: bool) -> IdTokenClaims {
+        serde_json::from_value(serde_json::json!({
+            "iss": OIDC_ISSUER,
+            "aud": OIDC_CLIENT_ID,
+            "exp": get_unix_time() + 60,
+            "sub": subject,
+            "email": email,
+            "email_verified": email_verified,
+            "preferred_username": "player",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn oidc_logins_never_take_over_accounts_by_email() {
+        init_test_statics();
+        let filename = test_db_path("oidc_user");
+        let mut db = DB::new(&filename);
+        db.set("player".to_string(), test_user("player", Role::Player))
+            .unwrap();
+
+        // the verified address of an existing account has to be linked from its settings
+        let claims = id_token_claims("subject-1", "Player@example.com", true);
+        assert_eq!(db.oidc_user(&claims).unwrap(), None);
+        assert_eq!(db.get("player").unwrap().oidc_subject, None);
+
+        // an unverified one gets a new account, without the address and under a free name
+        let claims = id_token_claims("subject-2", "player@example.com", false);
+        let username = db.oidc_user(&claims).unwrap().unwrap();
+        assert_eq!(username, "player2");
+        let user = db.get(&username).unwrap();
+        assert_eq!(user.email, "");
+        assert_eq!(user.oidc_subject.as_deref(), Some("subject-2"));
+        assert_eq!(db.oidc_user(&claims).unwrap().as_deref(), Some("player2"));
+        assert_eq!(db.oidc_subject_owner("subject-2"), Some("player2"));
+        let _ = std::fs::remove_file(filename);
+    }
+
+    #[test]
+    fn oidc_subjects_link_to_one_account_only() {
+        init_test_statics();
+        let filename = test_db_path("link_oidc");
+        let mut db = DB::new(&filename);
+        for username in ["player", "other"] {
+            db.set(username.to_string(), test_user(username, Role::Player))
+                .unwrap();
+        }
+        assert_eq!(db.link_oidc("player", "subject-1").unwrap(), Ok(()));
+        assert_eq!(
+            db.link_oidc("player", "subject-1").unwrap(),
+            Err("Single sign-on account is already linked")
+        );
+        assert_eq!(
+            db.link_oidc("other", "subject-1").unwrap(),
+            Err("Single sign-on account is linked to another account")
+        );
+        assert_eq!(db.get("other").unwrap().oidc_subject, None);
+        let claims = id_token_claims("subject-1", "player@example.com", true);
+        assert_eq!(db.oidc_user(&claims).unwrap().as_deref(), Some("player"));
+        let _ = std::fs::remove_file(filename);
+    }
+}
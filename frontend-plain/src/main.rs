@@ -26,6 +26,15 @@ fn initialize_frontend_cache() -> Result<(), Box<dyn Error>> {
     cache.insert("", load_template("templates/index.html")?);
     cache.insert("register", load_template("templates/register.html")?);
     cache.insert("login", load_template("templates/login.html")?);
+    cache.insert("oidc", load_template("templates/oidc.html")?);
+    cache.insert(
+        "forgot_password",
+        load_template("templates/forgot_password.html")?,
+    );
+    cache.insert(
+        "reset_password",
+        load_template("templates/reset_password.html")?,
+    );
     cache.insert("challenges", load_template("templates/challenges.html")?);
     cache.insert("scoreboard", load_template("templates/scoreboard.html")?);
     cache.insert(
@@ -23,7 +23,8 @@ async function showInfo(type, msg) {
     if (cur_endpoint == "/challenges" || cur_endpoint == "/" || cur_endpoint == "/logout" || cur_endpoint == "/profile") {
         document.getElementById('banner-box').insertAdjacentHTML('beforebegin', '<article id="' + id + '" style="'
             + style + '"><p style="text-align: center;">' + icon + ' ' + msg + '</p></article>');
-    } else if (cur_endpoint == "/register" || cur_endpoint == "/login") {
+    } else if (cur_endpoint == "/register" || cur_endpoint == "/login" || cur_endpoint == "/forgot_password"
+        || cur_endpoint == "/reset_password") {
         document.getElementById('main').insertAdjacentHTML('afterbegin', '<article id="' + id + '" style="'
             + style + '"><p style="text-align: center;">' + icon + ' ' + msg + '</p></article>');
     }
@@ -149,6 +150,7 @@ async function register() {
         'email': document.getElementById('email').value,
         'password': document.getElementById('password').value,
         'confirm_password': document.getElementById('confirm_password').value,
+        'invite_code': document.getElementById('invite_code').value,
     };
 
     const response = await fetch('{{ backend_addr }}/register', {
@@ -168,6 +170,7 @@ async function register() {
         document.getElementById('email').value = "";
         document.getElementById('password').value = "";
         document.getElementById('confirm_password').value = "";
+        document.getElementById('invite_code').value = "";
         showInfo("success", "Register successful!");
     } else {
         const cause = response_json["cause"];
@@ -185,6 +188,7 @@ async function login() {
     const user = {
         'username': document.getElementById('username').value,
         'password': document.getElementById('password').value,
+        'totp_code': document.getElementById('totp_code').value,
     };
 
     const response = await fetch('{{ backend_addr }}/login', {
@@ -202,6 +206,7 @@ async function login() {
         document.getElementById('submit').remove();
         document.getElementById('username').value = "";
         document.getElementById('password').value = "";
+        document.getElementById('totp_code').value = "";
         showInfo("success", "Login successful!");
         // recreate solved_chals cookie
         setCookieArray("solved_chals", response_json["solved_chals"]);
@@ -210,6 +215,13 @@ async function login() {
         // set "logged_as" cookie
         setCookie("logged_as", user["username"]);
         handleMenu();
+        if (response_json["totp_setup_required"]) {
+            showInfo("warning", "Logged in, but you have to set up two-factor authentication before submitting flags.");
+        }
+    } else if (response_json["status"] == "2fa_required") {
+        document.getElementById('totp').hidden = false;
+        document.getElementById('totp_code').focus();
+        showInfo("warning", "Enter the code from your authenticator app.");
     } else {
         const cause = response_json["cause"];
         console.log("Login failed: " + cause);
@@ -221,6 +233,330 @@ async function login() {
     }
 }
 
+function oidcLogin() {
+    window.location.href = '{{ backend_addr }}/oidc/login';
+}
+
+// the backend redirects here after the identity provider, with the result in the fragment
+async function oidcCallback() {
+    const result = new URLSearchParams(window.location.hash.substring(1));
+    history.replaceState(null, "", window.location.pathname);
+
+    if (result.get("status") == "OK") {
+        console.log("Login successful");
+        setCookieArray("solved_chals", JSON.parse(result.get("solved_chals")));
+        setCookie("auth_key", result.get("auth_key"));
+        setCookie("logged_as", result.get("username"));
+        handleMenu();
+        if (result.get("totp_setup_required") == "true") {
+            showInfo("warning", "Logged in, but you have to set up two-factor authentication before submitting flags.");
+        } else {
+            showInfo("success", "Login successful!");
+        }
+    } else if (result.get("status") == "link_pending") {
+        // only the session which started the link can finish it
+        const response = await fetch('{{ backend_addr }}/oidc/link/confirm', {
+            method: 'POST',
+            headers: {
+                'Accept': 'application/json',
+                'Content-Type': 'application/json'
+            },
+            body: JSON.stringify({ username: getCookie('logged_as'), auth_key: getCookie('auth_key'), link_token: result.get("link_token") }),
+        });
+        const response_json = await response.json();
+        if (response_json["status"] == "OK") {
+            showInfo("success", "Single sign-on account linked");
+        } else {
+            showInfo("warning", response_json["cause"]);
+        }
+    } else if (result.get("status") == "2fa_required") {
+        sessionStorage.setItem("login_token", result.get("login_token"));
+        sessionStorage.setItem("login_username", result.get("username"));
+        document.getElementById('totp').hidden = false;
+        document.getElementById('totp_code').focus();
+        showInfo("warning", "Enter the code from your authenticator app.");
+    } else {
+        const cause = result.get("cause") || "Login failed";
+        console.log("Login failed: " + cause);
+        showInfo("warning", cause);
+    }
+}
+
+async function oidcLink() {
+    const response = await fetch('{{ backend_addr }}/oidc/link', {
+        method: 'POST',
+        headers: {
+            'Accept': 'application/json',
+            'Content-Type': 'application/json'
+        },
+        body: JSON.stringify({ username: getCookie('logged_as'), auth_key: getCookie('auth_key') }),
+    });
+    const response_json = await response.json();
+
+    if (response_json["status"] == "OK") {
+        window.location.href = response_json["url"];
+    } else {
+        showInfo("warning", response_json["cause"]);
+    }
+}
+
+async function oidcLogin2fa() {
+    const request = {
+        'login_token': sessionStorage.getItem("login_token") || '',
+        'totp_code': document.getElementById('totp_code').value,
+    };
+
+    const response = await fetch('{{ backend_addr }}/login/2fa', {
+        method: 'POST',
+        headers: {
+            'Accept': 'application/json',
+            'Content-Type': 'application/json'
+        },
+        body: JSON.stringify(request),
+    });
+    const response_json = await response.json();
+
+    if (response_json["status"] == "OK") {
+        console.log("Login successful");
+        document.getElementById('totp').remove();
+        setCookieArray("solved_chals", response_json["solved_chals"]);
+        setCookie("auth_key", response_json["auth_key"]);
+        setCookie("logged_as", sessionStorage.getItem("login_username"));
+        sessionStorage.removeItem("login_token");
+        sessionStorage.removeItem("login_username");
+        showInfo("success", "Login successful!");
+        handleMenu();
+    } else {
+        const cause = response_json["cause"];
+        console.log("Login failed: " + cause);
+        document.getElementById('submit').disabled = true;
+        showInfo("warning", cause);
+        setTimeout(function() {
+            document.getElementById('submit').disabled = false;
+        }, 1000);
+    }
+}
+
+async function forgotPassword() {
+    console.log("Requesting password reset");
+    const request = {
+        'username': document.getElementById('username').value,
+    };
+
+    const response = await fetch('{{ backend_addr }}/forgot_password', {
+        method: 'POST',
+        headers: {
+            'Accept': 'application/json',
+            'Content-Type': 'application/json'
+        },
+        body: JSON.stringify(request),
+    });
+    const response_json = await response.json();
+
+    if (response_json["status"] == "OK") {
+        document.getElementById('submit').remove();
+        showInfo("success", response_json["message"]);
+    } else {
+        showInfo("warning", response_json["cause"]);
+    }
+}
+
+async function resetPassword() {
+    console.log("Resetting password");
+    const request = {
+        'token': new URLSearchParams(window.location.search).get('token') || '',
+        'password': document.getElementById('password').value,
+        'confirm_password': document.getElementById('confirm_password').value,
+    };
+
+    const response = await fetch('{{ backend_addr }}/reset_password', {
+        method: 'POST',
+        headers: {
+            'Accept': 'application/json',
+            'Content-Type': 'application/json'
+        },
+        body: JSON.stringify(request),
+    });
+    const response_json = await response.json();
+
+    if (response_json["status"] == "OK") {
+        console.log("Password reset successful");
+        document.getElementById('submit').remove();
+        document.getElementById('password').value = "";
+        document.getElementById('confirm_password').value = "";
+        showInfo("success", "Password changed, you can log in now");
+    } else {
+        const cause = response_json["cause"];
+        console.log("Password reset failed: " + cause);
+        document.getElementById('submit').disabled = true;
+        showInfo("warning", cause);
+        setTimeout(function() {
+            document.getElementById('submit').disabled = false;
+        }, 1000);
+    }
+}
+
+// the player's own flags of the dynamic challenges by challenge id, null when logged out
+async function getDynamicFlags() {
+    if (getCookie('logged_as') == '') {
+        return null;
+    }
+    const response = await fetch('{{ backend_addr }}/dynamic_flags', {
+        method: 'POST',
+        headers: {
+            'Accept': 'application/json',
+            'Content-Type': 'application/json'
+        },
+        body: JSON.stringify({ username: getCookie('logged_as'), auth_key: getCookie('auth_key') }),
+    });
+    const response_json = await response.json();
+    if (response_json["status"] != "OK") {
+        return {};
+    }
+    return response_json["flags"];
+}
+
+// replaces the flag placeholder in a description with the player's dynamic flag
+function fillDynamicFlag(text, challenge_id, dynamic_flags) {
+    // split so the template engine leaves it alone
+    const placeholder = '{' + '{flag}}';
+    if (dynamic_flags == null) {
+        return text.replaceAll(placeholder, '(log in to see your flag)');
+    }
+    if (dynamic_flags[challenge_id] == undefined) {
+        return text;
+    }
+    return text.replaceAll(placeholder, dynamic_flags[challenge_id]);
+}
+
+// texts of the paid hints the player already unlocked, by "challengeid_hintindex"
+async function getUnlockedHints() {
+    var hints = {};
+    if (getCookie('logged_as') == '') {
+        return hints;
+    }
+    const response = await fetch('{{ backend_addr }}/unlocked_hints', {
+        method: 'POST',
+        headers: {
+            'Accept': 'application/json',
+            'Content-Type': 'application/json'
+        },
+        body: JSON.stringify({ username: getCookie('logged_as'), auth_key: getCookie('auth_key') }),
+    });
+    const response_json = await response.json();
+    if (response_json["status"] == "OK") {
+        for (const hint of response_json["hints"]) {
+            hints[hint["challenge_id"] + '_' + hint["hint"]] = hint["text"];
+        }
+    }
+    return hints;
+}
+
+async function unlockHint(challenge_id, hint_id) {
+    const response = await fetch('{{ backend_addr }}/hint_unlock', {
+        method: 'POST',
+        headers: {
+            'Accept': 'application/json',
+            'Content-Type': 'application/json'
+        },
+        body: JSON.stringify({
+            username: getCookie('logged_as'),
+            auth_key: getCookie('auth_key'),
+            challenge_id: challenge_id,
+            hint: hint_id
+        }),
+    });
+    const response_json = await response.json();
+    if (response_json["status"] == "OK") {
+        document.getElementById('hint_' + challenge_id + '_' + hint_id).textContent = response_json["text"];
+    } else {
+        showInfo("warning", response_json["cause"]);
+    }
+}
+
+async function listApiTokens() {
+    const response = await fetch('{{ backend_addr }}/api_tokens', {
+        method: 'POST',
+        headers: {
+            'Accept': 'application/json',
+            'Content-Type': 'application/json'
+        },
+        body: JSON.stringify({ username: getCookie('logged_as'), auth_key: getCookie('auth_key') }),
+    });
+    const response_json = await response.json();
+    if (response_json["status"] != "OK") {
+        showInfo("warning", response_json["cause"]);
+        return;
+    }
+
+    const list = document.getElementById('api_tokens_list');
+    list.replaceChildren();
+    for (const token of response_json["tokens"]) {
+        const row = document.createElement('tr');
+        const last_used = token["last_used"] ? new Date(token["last_used"] * 1000).toLocaleString() : "never";
+        for (const text of [token["name"], token["scope"], new Date(token["created"] * 1000).toLocaleString(), last_used]) {
+            const cell = document.createElement('td');
+            cell.textContent = text;
+            row.appendChild(cell);
+        }
+        const revoke = document.createElement('input');
+        revoke.type = "button";
+        revoke.value = "Revoke";
+        revoke.onclick = function() { revokeApiToken(token["id"]); };
+        const cell = document.createElement('td');
+        cell.appendChild(revoke);
+        row.appendChild(cell);
+        list.appendChild(row);
+    }
+}
+
+async function createApiToken() {
+    const request = {
+        'username': getCookie('logged_as'),
+        'auth_key': getCookie('auth_key'),
+        'name': document.getElementById('token_name').value,
+        'scope': document.getElementById('token_scope').value,
+    };
+
+    const response = await fetch('{{ backend_addr }}/api_tokens/create', {
+        method: 'POST',
+        headers: {
+            'Accept': 'application/json',
+            'Content-Type': 'application/json'
+        },
+        body: JSON.stringify(request),
+    });
+    const response_json = await response.json();
+
+    if (response_json["status"] == "OK") {
+        document.getElementById('token_name').value = "";
+        document.getElementById('new_token').textContent = response_json["token"];
+        document.getElementById('new_token_box').hidden = false;
+        listApiTokens();
+    } else {
+        showInfo("warning", response_json["cause"]);
+    }
+}
+
+async function revokeApiToken(id) {
+    const response = await fetch('{{ backend_addr }}/api_tokens/revoke', {
+        method: 'POST',
+        headers: {
+            'Accept': 'application/json',
+            'Content-Type': 'application/json'
+        },
+        body: JSON.stringify({ username: getCookie('logged_as'), auth_key: getCookie('auth_key'), id: id }),
+    });
+    const response_json = await response.json();
+
+    if (response_json["status"] == "OK") {
+        showInfo("success", "Token revoked");
+        listApiTokens();
+    } else {
+        showInfo("warning", response_json["cause"]);
+    }
+}
+
 async function handleMenu() {
     var but1 = document.getElementById("menu_additional1");
     var but2 = document.getElementById("menu_additional2");
@@ -253,6 +589,8 @@ async function main() {
 
         var main = document.getElementById('main');
         var solved_chals = getCookieArray("solved_chals");
+        const dynamic_flags = await getDynamicFlags();
+        const unlocked_hints = await getUnlockedHints();
 
         for (var cat_id = 0; cat_id < challenges.length; cat_id++) {
             var category = document.createElement('h3');
@@ -270,14 +608,51 @@ async function main() {
                     challenge_obj.style = "background-color: #1d4d1d;";
                 }
 
-                challenge_obj.insertAdjacentHTML('beforeend', '<summary>' + challenge["name"]
-                    + ' - <em style="color:var(--accent);">' + challenge["points"] + '</em></summary>');
-
-                challenge_obj.insertAdjacentHTML('beforeend', '<p>' + challenge["description"] + '</p>');
+                var summary = document.createElement('summary');
+                summary.textContent = challenge["name"] + ' - ';
+                var points = document.createElement('em');
+                points.style = "color:var(--accent);";
+                points.textContent = challenge["points"];
+                summary.appendChild(points);
+                if (challenge["first_blood"] != undefined) {
+                    var badge = document.createElement('small');
+                    badge.title = "First blood";
+                    badge.textContent = ' 🩸 ' + challenge["first_blood"];
+                    summary.appendChild(badge);
+                }
+                challenge_obj.appendChild(summary);
 
-                if (challenge["hint"] != undefined) {
-                    challenge_obj.insertAdjacentHTML('beforeend', '<details><summary>Hint</summary><p>'
-                        + challenge["hint"] + '</p></details>');
+                // only descriptions marked as trusted in the config are rendered as HTML
+                var description = document.createElement('p');
+                const description_text = fillDynamicFlag(challenge["description"], challenge["id"], dynamic_flags);
+                if (challenge["trusted_html"]) {
+                    description.innerHTML = description_text;
+                } else {
+                    description.textContent = description_text;
+                }
+                challenge_obj.appendChild(description);
+
+                for (var hint_id = 0; hint_id < challenge["hints"].length; hint_id++) {
+                    const hint_data = challenge["hints"][hint_id];
+                    var hint = document.createElement('details');
+                    var hint_summary = document.createElement('summary');
+                    hint_summary.textContent = 'Hint ' + (hint_id + 1);
+                    if (hint_data["cost"] > 0) {
+                        hint_summary.textContent += ' (' + hint_data["cost"] + ' points)';
+                    }
+                    hint.appendChild(hint_summary);
+                    var hint_text = document.createElement('p');
+                    hint_text.id = 'hint_' + challenge["id"] + '_' + hint_id;
+                    // paid hints only come with the unlock
+                    const text = hint_data["text"] ?? unlocked_hints[challenge["id"] + '_' + hint_id];
+                    if (text != undefined) {
+                        hint_text.textContent = text;
+                    } else {
+                        hint_text.insertAdjacentHTML('beforeend', '<input type="button" value="Unlock for '
+                            + hint_data["cost"] + ' points" onclick="unlockHint(' + challenge["id"] + ', ' + hint_id + ');">');
+                    }
+                    hint.appendChild(hint_text);
+                    challenge_obj.appendChild(hint);
                 }
 
                 if (solved_chals.includes(challenge["id"])) {
@@ -315,13 +690,34 @@ async function main() {
         for (var user_id = 0; user_id < sb_data.length; user_id++) {
             var entry = document.createElement('tr');
 
-            entry.insertAdjacentHTML('beforeend', '<td>' + (user_id + 1) + '</td><td>' + sb_data[user_id]["username"]
-                + '</td><td>' + sb_data[user_id]["score"] + '</td>');
+            for (const value of [user_id + 1, sb_data[user_id]["username"], sb_data[user_id]["score"]]) {
+                var cell = document.createElement('td');
+                cell.textContent = value;
+                entry.appendChild(cell);
+            }
+
+            if (sb_data[user_id]["first_bloods"] > 0) {
+                var badge = document.createElement('small');
+                badge.title = "First bloods";
+                badge.textContent = ' 🩸' + sb_data[user_id]["first_bloods"];
+                entry.children[1].appendChild(badge);
+            }
 
             scoreboard.appendChild(entry);
         }
     } else if (cur_endpoint == "/logout") {
         console.log("Logging out");
+        // revoke the session on the backend before forgetting it
+        if (getCookie('logged_as') != '') {
+            await fetch('{{ backend_addr }}/logout', {
+                method: 'POST',
+                headers: {
+                    'Accept': 'application/json',
+                    'Content-Type': 'application/json'
+                },
+                body: JSON.stringify({ username: getCookie('logged_as'), auth_key: getCookie('auth_key') }),
+            });
+        }
         const cookies = document.cookie.split(';');
 
         for (let i=0; i<cookies.length; i++) {
@@ -345,16 +741,27 @@ async function main() {
                 'Accept': 'application/json',
                 'Content-Type': 'application/json'
             },
-            body: JSON.stringify({ username: username }),
+            body: JSON.stringify({ username: username, auth_key: getCookie('auth_key') }),
         });
         const data = await response.json();
         if (data["status"] == "OK") {
             const box = document.getElementById('banner-box');
+            if (data["hint_costs"] > 0) {
+                box.insertAdjacentHTML('afterend', '<p>Spent on hints: ' + data["hint_costs"] + '</p>');
+            }
             box.insertAdjacentHTML('afterend', '<p>Score: ' + data["score"] + '</p>');
-            box.insertAdjacentHTML('afterend', '<h2>' + username + '</h2>');
+            box.insertAdjacentHTML('afterend', '<p>Role: ' + data["role"] + '</p>');
+            const heading = document.createElement('h2');
+            heading.textContent = username;
+            box.after(heading);
+            document.getElementById('api_tokens').hidden = false;
+            document.getElementById('oidc_link').hidden = data["oidc_linked"];
+            listApiTokens();
         } else {
             showInfo("warning", data["cause"]);
         }
+    } else if (cur_endpoint == "/oidc") {
+        oidcCallback();
     }
 }
 